@@ -0,0 +1,307 @@
+use std::collections::HashSet;
+use std::fmt;
+use vcf::VCFRecord;
+
+use crate::fasta::Fasta;
+use crate::vcf_util::is_symbolic;
+
+/// Problem of an input record making it unfit for resolving overlaps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// The position is before that of the previous record on the contig
+    Unsorted { last: u64 },
+    /// The contig appears again after records of other contigs
+    ReappearingContig,
+    /// The REF and ALT alleles share a suffix that can be trimmed
+    SharedSuffix,
+    /// An indel lacks the base before the inserted or deleted sequence
+    MissingAnchor,
+    /// The REF allele does not match the reference sequence
+    RefMismatch,
+    /// An indel can be shifted to the left
+    NotLeftAligned,
+    /// The record cannot be checked against the reference sequence
+    Reference(String),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::Unsorted { last } => {
+                write!(f, "position before a previous one ({}) on the contig", last)
+            }
+            Issue::ReappearingContig => write!(f, "contig appears again after other contigs"),
+            Issue::SharedSuffix => write!(f, "REF and ALT alleles share a suffix"),
+            Issue::MissingAnchor => write!(f, "indel without an anchor base"),
+            Issue::RefMismatch => write!(f, "REF allele does not match the reference"),
+            Issue::NotLeftAligned => write!(f, "indel not left-aligned"),
+            Issue::Reference(msg) => write!(f, "cannot check against the reference: {}", msg),
+        }
+    }
+}
+
+/// Checker of the sortedness and normalisation of input records.
+///
+/// Records must be sorted by position within a contig and the records of a
+/// contig must be contiguous; the order of contigs is not checked. Records
+/// with symbolic, missing, or `*` ALT alleles are only checked for
+/// sortedness.
+#[derive(Default)]
+pub struct InputCheck {
+    last: Option<(Vec<u8>, u64)>,
+    /// Contigs whose records are over
+    finished: HashSet<Vec<u8>>,
+}
+
+impl InputCheck {
+    /// Check the next record.
+    ///
+    /// It returns the first issue found, if any; left alignment is only
+    /// checked if the reference sequences are given.
+    pub fn check(&mut self, record: &VCFRecord, fasta: Option<&mut Fasta>) -> Option<Issue> {
+        if let Some(issue) = self.check_order(record) {
+            return Some(issue);
+        }
+        if record
+            .alternative
+            .iter()
+            .any(|a| is_symbolic(a) || a == b"*" || a == b"." || a.is_empty())
+        {
+            return None;
+        }
+        check_suffix(record)
+            .or_else(|| check_anchor(record))
+            .or_else(|| fasta.and_then(|fasta| check_alignment(record, fasta)))
+    }
+
+    fn check_order(&mut self, record: &VCFRecord) -> Option<Issue> {
+        let issue = match self.last.take() {
+            Some((chrom, pos)) if chrom == record.chromosome => {
+                (record.position < pos).then_some(Issue::Unsorted { last: pos })
+            }
+            Some((chrom, _)) => {
+                self.finished.insert(chrom);
+                self.finished
+                    .contains(&record.chromosome)
+                    .then_some(Issue::ReappearingContig)
+            }
+            None => None,
+        };
+        let pos = match issue {
+            // keep comparing with the furthest position seen
+            Some(Issue::Unsorted { last }) => last,
+            _ => record.position,
+        };
+        self.last = Some((record.chromosome.clone(), pos));
+        issue
+    }
+}
+
+/// Check whether all alleles of a record share a trimmable suffix.
+fn check_suffix(record: &VCFRecord) -> Option<Issue> {
+    let last = record.reference.last()?;
+    let shared = record.reference.len() > 1
+        && record
+            .alternative
+            .iter()
+            .all(|a| a.len() > 1 && a.last().unwrap().eq_ignore_ascii_case(last));
+    shared.then_some(Issue::SharedSuffix)
+}
+
+/// Trim the shared suffix and then the shared prefix of the REF and an ALT
+/// allele.
+///
+/// It returns the length of the trimmed prefix and the remaining alleles.
+fn trim<'a>(reference: &'a [u8], alt: &'a [u8]) -> (usize, &'a [u8], &'a [u8]) {
+    let (mut r, mut a) = (reference, alt);
+    while let (Some(x), Some(y)) = (r.last(), a.last()) {
+        if !x.eq_ignore_ascii_case(y) {
+            break;
+        }
+        r = &r[..r.len() - 1];
+        a = &a[..a.len() - 1];
+    }
+    let mut prefix = 0;
+    while let (Some(x), Some(y)) = (r.first(), a.first()) {
+        if !x.eq_ignore_ascii_case(y) {
+            break;
+        }
+        r = &r[1..];
+        a = &a[1..];
+        prefix += 1;
+    }
+    (prefix, r, a)
+}
+
+/// Check whether an indel of a record lacks the base before the inserted or
+/// deleted sequence.
+fn check_anchor(record: &VCFRecord) -> Option<Issue> {
+    let reference = &record.reference;
+    let missing = record.alternative.iter().any(|alt| {
+        alt.len() != reference.len() && !alt[0].eq_ignore_ascii_case(&reference[0]) && {
+            let (_, r, a) = trim(reference, alt);
+            r.is_empty() || a.is_empty()
+        }
+    });
+    missing.then_some(Issue::MissingAnchor)
+}
+
+/// Check the REF allele of a record against the reference sequence and
+/// whether its indels are left-aligned.
+///
+/// An indel is left-aligned if the base before the inserted or deleted
+/// sequence differs from the last base of the sequence.
+fn check_alignment(record: &VCFRecord, fasta: &mut Fasta) -> Option<Issue> {
+    if record.position == 0 {
+        let msg = "position 0 before the reference sequence".to_string();
+        return Some(Issue::Reference(msg));
+    }
+    let chrom = &record.chromosome;
+    let start = record.position.saturating_sub(1).max(1);
+    let end = record.position + record.reference.len() as u64 - 1;
+    let sequence = match fasta.fetch(chrom, start, end) {
+        Ok(sequence) => sequence,
+        Err(e) => return Some(Issue::Reference(e.to_string())),
+    };
+    let offset = (record.position - start) as usize;
+    if !sequence[offset..].eq_ignore_ascii_case(&record.reference) {
+        return Some(Issue::RefMismatch);
+    }
+    for alt in record.alternative.iter() {
+        let (prefix, r, a) = trim(&record.reference, alt);
+        let indel = match (r.is_empty(), a.is_empty()) {
+            (true, false) => a,
+            (false, true) => r,
+            _ => continue,
+        };
+        // the base before the indel is in the record or right before it
+        let before = match (offset + prefix).checked_sub(1) {
+            Some(idx) => sequence[idx],
+            None => continue,
+        };
+        if before.eq_ignore_ascii_case(indel.last().unwrap()) {
+            return Some(Issue::NotLeftAligned);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta::TempFasta;
+    use crate::vcf_util::parse_vcf;
+
+    /// Parse records of `CHROM POS REF ALT` lines.
+    fn records(sites: &[&str]) -> Vec<VCFRecord> {
+        let mut text =
+            "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n".to_string();
+        for site in sites {
+            let fields: Vec<&str> = site.split(' ').collect();
+            text.push_str(&format!(
+                "{}\t{}\t.\t{}\t{}\t.\t.\t.\n",
+                fields[0], fields[1], fields[2], fields[3]
+            ));
+        }
+        parse_vcf(&text).1
+    }
+
+    #[test]
+    fn test_check_order() {
+        let sites = [
+            "x 5 A C", "x 3 A C", "x 4 A C", "x 6 A C", "y 1 A C", "x 7 A C", "x 8 A C", "y 2 A C",
+        ];
+        let mut input_check = InputCheck::default();
+        let issues: Vec<Option<Issue>> = records(&sites)
+            .iter()
+            .map(|r| input_check.check(r, None))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                None,
+                Some(Issue::Unsorted { last: 5 }),
+                // compared with the furthest position seen
+                Some(Issue::Unsorted { last: 5 }),
+                None,
+                None,
+                Some(Issue::ReappearingContig),
+                None,
+                Some(Issue::ReappearingContig),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_normalisation() {
+        let sites = [
+            "x 1 AC GC",
+            "x 1 ACT GT,CT",
+            "x 1 AC A",
+            "x 1 AC C,GC",
+            "x 2 CG G",
+            "x 2 T GT",
+            "x 2 C CT",
+            "x 2 CG <DEL>",
+        ];
+        let issues: Vec<Option<Issue>> = records(&sites)
+            .iter()
+            .map(|r| InputCheck::default().check(r, None))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                Some(Issue::SharedSuffix),
+                Some(Issue::SharedSuffix),
+                None,
+                Some(Issue::MissingAnchor),
+                Some(Issue::MissingAnchor),
+                Some(Issue::MissingAnchor),
+                None,
+                // symbolic alleles are only checked for sortedness
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_alignment() {
+        let temp = TempFasta::new("check", ">x\nAGCACACTTA\n");
+        let mut fasta = Fasta::open(&temp.0).unwrap();
+        let sites = [
+            "x 2 GCA G",
+            "x 5 CAC C",
+            "x 2 G GCA",
+            "x 7 C CAC",
+            "x 8 T TT",
+            "x 9 T TT",
+            "x 4 A G",
+            "x 4 C T",
+            "x 0 A C",
+            "x 10 AC A",
+            "y 1 A C",
+        ];
+        let issues: Vec<Option<Issue>> = records(&sites)
+            .iter()
+            .map(|r| InputCheck::default().check(r, Some(&mut fasta)))
+            .collect();
+        assert_eq!(
+            issues[..8],
+            [
+                None,
+                // the deleted AC can be shifted to 3
+                Some(Issue::NotLeftAligned),
+                None,
+                Some(Issue::NotLeftAligned),
+                None,
+                Some(Issue::NotLeftAligned),
+                None,
+                Some(Issue::RefMismatch),
+            ]
+        );
+        // position 0, past the sequence end, and unknown sequence
+        assert!(issues[8..]
+            .iter()
+            .all(|i| matches!(i, Some(Issue::Reference(_)))));
+    }
+}
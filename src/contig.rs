@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use vcf::{VCFError, VCFHeader, VCFRecord};
+
+use crate::vcf_util::RecordRead;
+
+/// Patterns of non-primary contigs excluded by `--primary-only`; i.e. ALT
+/// haplotypes, decoys, HLA alleles, unplaced and unlocalised scaffolds, and
+/// EBV of GRCh38 analysis sets.
+pub const NON_PRIMARY_CONTIGS: &[&str] = &[
+    "*_alt", "*_decoy", "hs37d5", "HLA-*", "chrUn*", "Un_*", "*_random", "chrEBV",
+];
+
+/// Match a contig name against a glob pattern.
+///
+/// `*` matches any sequence of characters and `?` matches a single one.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // position of the last '*' in the pattern and the name position it matched
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Load contig names or patterns from a file; one per line.
+pub fn load_patterns<T>(path: &T) -> Result<Vec<String>, std::io::Error>
+where
+    T: AsRef<Path>,
+{
+    let reader = BufReader::new(File::open(path)?);
+    let mut patterns = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            patterns.push(line.to_string());
+        }
+    }
+    Ok(patterns)
+}
+
+/// Selector of contigs by allow and deny lists of glob patterns.
+///
+/// A contig is selected if it matches any allowed pattern, or there is no
+/// allow list, and it matches no denied pattern.
+pub struct ContigSelector {
+    include: Option<Vec<Vec<u8>>>,
+    exclude: Vec<Vec<u8>>,
+    cache: HashMap<Vec<u8>, bool>,
+}
+
+impl ContigSelector {
+    pub fn new(include: Option<Vec<String>>, exclude: Vec<String>) -> Self {
+        ContigSelector {
+            include: include.map(|v| v.into_iter().map(String::into_bytes).collect()),
+            exclude: exclude.into_iter().map(String::into_bytes).collect(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Check whether a contig is selected.
+    pub fn is_selected(&self, name: &[u8]) -> bool {
+        let included = self
+            .include
+            .as_ref()
+            .is_none_or(|patterns| patterns.iter().any(|p| glob_match(p, name)));
+        included && !self.exclude.iter().any(|p| glob_match(p, name))
+    }
+
+    /// Check whether a contig is selected, caching the result.
+    fn is_selected_cached(&mut self, name: &[u8]) -> bool {
+        if let Some(selected) = self.cache.get(name) {
+            return *selected;
+        }
+        let selected = self.is_selected(name);
+        self.cache.insert(name.to_vec(), selected);
+        selected
+    }
+}
+
+/// Record source skipping the records on unselected contigs by streaming the
+/// whole input.
+///
+/// It is used when no index is available for random access.
+pub struct ContigFilter<S: RecordRead> {
+    source: S,
+    selector: ContigSelector,
+}
+
+impl<S: RecordRead> ContigFilter<S> {
+    pub fn new(source: S, selector: ContigSelector) -> Self {
+        ContigFilter { source, selector }
+    }
+}
+
+impl<S: RecordRead> RecordRead for ContigFilter<S> {
+    fn header(&self) -> &VCFHeader {
+        self.source.header()
+    }
+
+    fn next_record(&mut self, record: &mut VCFRecord) -> Result<bool, VCFError> {
+        while self.source.next_record(record)? {
+            if self.selector.is_selected_cached(&record.chromosome) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        glob_match(pattern.as_bytes(), name.as_bytes())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(matches("chr1", "chr1"));
+        assert!(!matches("chr1", "chr10"));
+        assert!(!matches("chr10", "chr1"));
+        // '*' at the start, middle, and end
+        assert!(matches("*_alt", "chr1_KI270762v1_alt"));
+        assert!(!matches("*_alt", "chr1_alt_x"));
+        assert!(matches("chr*_alt", "chr1_KI270762v1_alt"));
+        assert!(matches("chrUn*", "chrUn"));
+        assert!(matches("chrUn*", "chrUn_KI270302v1"));
+        assert!(!matches("chrUn*", "chr1"));
+        assert!(matches("*", "chr1"));
+        assert!(matches("**", "chr1"));
+        // '?' matches exactly one character
+        assert!(matches("chr?", "chrX"));
+        assert!(!matches("chr?", "chr"));
+        assert!(!matches("chr?", "chr10"));
+        assert!(matches("chr?*", "chr10"));
+        // backtracking after a partial match of the part following '*'
+        assert!(matches("chr*_random", "chr1_KI270706v1_random"));
+        assert!(matches("*_random", "chr1_random_random"));
+        assert!(!matches("chr*_random", "chr1_random_alt"));
+        // empty pattern or name
+        assert!(matches("", ""));
+        assert!(!matches("", "chr1"));
+        assert!(!matches("chr1", ""));
+        assert!(matches("*", ""));
+        assert!(!matches("?", ""));
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::RangeInclusive;
+use vcf::VCFRecord;
+
+use crate::vcf_util::{Emitted, RecordStage, Staged};
+
+/// Layout of genomic windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowMode {
+    /// Every window of the given size; i.e. starting at any position
+    Sliding,
+    /// Non-overlapping windows starting at positions 1, W+1, 2W+1, ...
+    Tiled,
+}
+
+impl std::str::FromStr for WindowMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sliding" => Ok(WindowMode::Sliding),
+            "tiled" => Ok(WindowMode::Tiled),
+            _ => Err(format!(
+                "invalid window mode '{}': expected 'sliding' or 'tiled'",
+                s
+            )),
+        }
+    }
+}
+
+/// A buffered record waiting for the decision of the density cap.
+struct Entry {
+    staged: Staged,
+    kept: Option<bool>,
+}
+
+impl Entry {
+    fn pos(&self) -> u64 {
+        self.staged.record.position
+    }
+
+    fn record(&self) -> &VCFRecord {
+        &self.staged.record
+    }
+
+    fn is_capped(&self) -> bool {
+        self.staged.class.is_some()
+    }
+}
+
+/// Cap on the number of records in every genomic window.
+///
+/// Capped records are visited greedily in the order of rank and kept if no
+/// window containing them has `max` kept records yet; so that no window has
+/// more than `max` capped records. Ties are broken by the input order. For
+/// tiled windows, this keeps exactly the best `max` records of each tile.
+///
+/// Records must be pushed in coordinate order. A record is decided once all
+/// better-ranked records sharing a window with it are decided. Along runs of
+/// records ranked better towards the right, sliding windows would chain this
+/// wait without bound; so records two window lengths behind the newest one
+/// are decided given the records kept so far. This bounds the buffer and
+/// never breaks the cap, but may keep a different record than the greedy
+/// order along such runs. Records in tiled windows are decided as soon as
+/// their tile ends, so the reach does not change their outcome.
+pub struct DensityCap {
+    max: usize,
+    window: u64,
+    mode: WindowMode,
+    /// Distance behind the newest record at which records are decided
+    reach: u64,
+    buffer: VecDeque<Entry>,
+    /// Sequence number of the front of the buffer
+    front: usize,
+    /// Undecided records by rank, input index, and sequence number
+    undecided: BTreeSet<(usize, usize, usize)>,
+    /// Positions of the kept capped records in the buffer, with their counts
+    kept: BTreeMap<u64, usize>,
+    removed: usize,
+}
+
+impl DensityCap {
+    pub fn new(max: usize, window: u64, mode: WindowMode) -> Self {
+        let window = std::cmp::max(window, 1);
+        DensityCap {
+            max,
+            window,
+            mode,
+            reach: window.saturating_mul(2),
+            buffer: VecDeque::new(),
+            front: 0,
+            undecided: BTreeSet::new(),
+            kept: BTreeMap::new(),
+            removed: 0,
+        }
+    }
+
+    /// Start position of the tile containing a position.
+    fn tile_start(&self, pos: u64) -> u64 {
+        (pos.saturating_sub(1) / self.window) * self.window + 1
+    }
+
+    /// Check whether no record at or after `pos` shares a window with the
+    /// record at `other`.
+    fn is_apart(&self, other: u64, pos: u64) -> bool {
+        match self.mode {
+            WindowMode::Sliding => pos >= other + self.window,
+            WindowMode::Tiled => self.tile_start(pos) != self.tile_start(other),
+        }
+    }
+
+    /// Range of the positions sharing a window with a position.
+    fn shared_range(&self, pos: u64) -> RangeInclusive<u64> {
+        match self.mode {
+            WindowMode::Sliding => {
+                pos.saturating_sub(self.window - 1)..=pos.saturating_add(self.window - 1)
+            }
+            WindowMode::Tiled => {
+                let start = self.tile_start(pos);
+                start..=start + self.window - 1
+            }
+        }
+    }
+
+    /// Decide whether a capped record at `pos` is kept given the kept records.
+    fn decide(&self, pos: u64) -> bool {
+        // positions of the kept capped records sharing a window
+        let kept: Vec<u64> = self
+            .kept
+            .range(self.shared_range(pos))
+            .flat_map(|(p, n)| std::iter::repeat_n(*p, *n))
+            .collect();
+        let count = |start: u64| {
+            let end = start + self.window - 1;
+            kept.iter().filter(|p| start <= **p && **p <= end).count()
+        };
+        let starts: Vec<u64> = match self.mode {
+            WindowMode::Tiled => vec![self.tile_start(pos)],
+            WindowMode::Sliding => {
+                let first = pos.saturating_sub(self.window - 1).max(1);
+                // the windows with the most records start at the records
+                std::iter::once(first)
+                    .chain(kept.iter().filter(|p| first <= **p && **p <= pos).copied())
+                    .chain(
+                        kept.iter()
+                            .filter(|p| pos <= **p)
+                            .map(|p| (p + 1).saturating_sub(self.window))
+                            .filter(|s| *s >= first),
+                    )
+                    .collect()
+            }
+        };
+        starts.into_iter().all(|s| count(s) < self.max)
+    }
+
+    /// Decide the ready records in the order of rank, before a record at
+    /// `next`, if any, is pushed.
+    ///
+    /// A record is ready if no further record shares a window with it and no
+    /// undecided better-ranked record does, or if it is out of reach of
+    /// `next`.
+    fn decide_ready(&mut self, next: Option<u64>) {
+        let oldest = self
+            .buffer
+            .iter()
+            .find(|e| e.kept.is_none())
+            .map(|e| e.pos());
+        // no record is ready until the oldest undecided one is apart from next
+        match (oldest, next) {
+            (None, _) => return,
+            (Some(oldest), Some(next)) if !self.is_apart(oldest, next) => return,
+            _ => {}
+        }
+        // positions of the undecided records ranked better than the visited one
+        let mut blocked = BTreeSet::new();
+        let undecided: Vec<(usize, usize, usize)> = self.undecided.iter().copied().collect();
+        for key in undecided {
+            let seq = key.2;
+            let pos = self.buffer[seq - self.front].pos();
+            let due = next.is_some_and(|n| pos.saturating_add(self.reach) <= n);
+            let apart = next.is_none_or(|n| self.is_apart(pos, n));
+            let waits = !apart || blocked.range(self.shared_range(pos)).next().is_some();
+            if !due && waits {
+                blocked.insert(pos);
+                continue;
+            }
+            let kept = self.decide(pos);
+            self.buffer[seq - self.front].kept = Some(kept);
+            self.undecided.remove(&key);
+            if kept {
+                *self.kept.entry(pos).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn pop_front(&mut self, emitted: &mut Emitted) {
+        let entry = self.buffer.pop_front().unwrap();
+        self.front += 1;
+        if entry.kept == Some(true) {
+            if entry.is_capped() {
+                let pos = entry.pos();
+                if let Some(n) = self.kept.get_mut(&pos) {
+                    *n -= 1;
+                    if *n == 0 {
+                        self.kept.remove(&pos);
+                    }
+                }
+            }
+            emitted.kept.push(entry.staged);
+        } else {
+            self.removed += 1;
+            emitted.removed.push(entry.staged);
+        }
+    }
+}
+
+impl RecordStage for DensityCap {
+    fn push(&mut self, staged: Staged, emitted: &mut Emitted) {
+        let same_contig = self
+            .buffer
+            .back()
+            .is_none_or(|e| e.record().chromosome == staged.record.chromosome);
+        if !same_contig {
+            self.flush(emitted);
+        }
+        let pos = staged.record.position;
+        self.decide_ready(Some(pos));
+        // decided records are needed until no undecided one shares a window
+        while self.buffer.front().is_some_and(|e| e.kept.is_some()) {
+            let front = self.buffer[0].pos();
+            let needed = self
+                .buffer
+                .iter()
+                .find(|e| e.kept.is_none())
+                .map_or(pos, |e| e.pos());
+            if !self.is_apart(front, needed) {
+                break;
+            }
+            self.pop_front(emitted);
+        }
+        let seq = self.front + self.buffer.len();
+        // uncapped records are always kept
+        let kept = match staged.class {
+            Some(_) => {
+                self.undecided.insert((staged.rank, staged.idx, seq));
+                None
+            }
+            None => Some(true),
+        };
+        self.buffer.push_back(Entry { staged, kept });
+    }
+
+    fn flush(&mut self, emitted: &mut Emitted) {
+        self.decide_ready(None);
+        while !self.buffer.is_empty() {
+            self.pop_front(emitted);
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "Removed {} records exceeding {} per {}-bp window",
+            self.removed, self.max, self.window
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::{parse_vcf, xorshift, VariantClass};
+
+    /// Push records at the given positions and ranks on contig `x`, and get
+    /// the positions of the kept ones; a rank of `usize::MAX` is uncapped.
+    fn cap(max: usize, window: u64, mode: WindowMode, sites: &[(u64, usize)]) -> Vec<u64> {
+        run(DensityCap::new(max, window, mode), sites).0
+    }
+
+    /// Push records to a stage as `cap`, and get the positions of the kept
+    /// ones and the largest buffer length.
+    fn run(mut stage: DensityCap, sites: &[(u64, usize)]) -> (Vec<u64>, usize) {
+        let mut text =
+            "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n".to_string();
+        for (pos, _) in sites {
+            text.push_str(&format!("x\t{}\t.\tA\tC\t.\t.\t.\n", pos));
+        }
+        let (_, records) = parse_vcf(&text);
+        let mut emitted = Emitted::default();
+        let mut buffered = 0;
+        for (idx, ((_, rank), record)) in sites.iter().zip(records).enumerate() {
+            let class = (*rank != usize::MAX).then_some(VariantClass::Snv);
+            let staged = Staged {
+                idx,
+                rank: *rank,
+                class,
+                record,
+            };
+            stage.push(staged, &mut emitted);
+            buffered = buffered.max(stage.buffer.len());
+        }
+        stage.flush(&mut emitted);
+        let mut order: Vec<usize> = emitted
+            .kept
+            .iter()
+            .chain(emitted.removed.iter())
+            .map(|s| s.idx)
+            .collect();
+        order.sort_unstable();
+        assert_eq!(order, (0..sites.len()).collect::<Vec<_>>());
+        let kept = emitted.kept.iter().map(|s| s.record.position).collect();
+        (kept, buffered)
+    }
+
+    /// Keep greedily the capped sites in the order of rank if every window
+    /// containing them stays under the cap.
+    fn greedy(max: usize, window: u64, mode: WindowMode, sites: &[(u64, usize)]) -> Vec<u64> {
+        let stage = DensityCap::new(max, window, mode);
+        let mut order: Vec<usize> = (0..sites.len()).collect();
+        order.sort_by_key(|i| (sites[*i].1, *i));
+        let mut kept = vec![false; sites.len()];
+        for i in order {
+            let pos = sites[i].0;
+            let fits = sites[i].1 == usize::MAX
+                || (1..=pos).filter(|s| s + window > pos).all(|s| {
+                    let in_window = |p: u64| match mode {
+                        WindowMode::Sliding => s <= p && p < s + window,
+                        WindowMode::Tiled => stage.tile_start(p) == stage.tile_start(pos),
+                    };
+                    let count = (0..sites.len())
+                        .filter(|j| kept[*j] && sites[*j].1 != usize::MAX && in_window(sites[*j].0))
+                        .count();
+                    count < max
+                });
+            kept[i] = fits;
+        }
+        (0..sites.len())
+            .filter(|i| kept[*i])
+            .map(|i| sites[i].0)
+            .collect()
+    }
+
+    #[test]
+    fn test_density_cap() {
+        let sliding = WindowMode::Sliding;
+        // 9 is removed by 1, so 17 shares no window with a kept record
+        let sites = [(1, 1), (9, 2), (17, 3), (30, 4)];
+        assert_eq!(cap(1, 10, sliding, &sites), vec![1, 17, 30]);
+        // better ranks to the right decide from right to left
+        let sites = [(1, 4), (7, 3), (13, 2), (19, 1)];
+        assert_eq!(cap(1, 10, sliding, &sites), vec![7, 19]);
+        let sites = [(1, 3), (5, 1), (8, 2), (12, 4), (20, 5)];
+        assert_eq!(cap(2, 10, sliding, &sites), vec![5, 8, 20]);
+        // uncapped records are neither removed nor counted
+        let sites = [(1, usize::MAX), (2, 2), (3, 1)];
+        assert_eq!(cap(1, 10, sliding, &sites), vec![1, 3]);
+        // best record of each tile
+        let sites = [(1, 3), (9, 2), (11, 1), (20, 4), (21, 5)];
+        assert_eq!(cap(1, 10, WindowMode::Tiled, &sites), vec![9, 11, 21]);
+    }
+
+    #[test]
+    fn test_density_cap_greedy() {
+        // deterministic pseudo-random sites
+        let mut next = xorshift(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..300 {
+            let n = (next() % 20 + 1) as usize;
+            let mut pos = 0;
+            let sites: Vec<(u64, usize)> = (0..n)
+                .map(|_| {
+                    pos += next() % 6;
+                    let rank = match next() % 8 {
+                        0 => usize::MAX,
+                        r => r as usize,
+                    };
+                    (pos.max(1), rank)
+                })
+                .collect();
+            let max = (next() % 3 + 1) as usize;
+            let window = next() % 12 + 1;
+            for mode in [WindowMode::Sliding, WindowMode::Tiled] {
+                // tiled windows are decided within the reach
+                let mut stage = DensityCap::new(max, window, mode);
+                if mode == WindowMode::Sliding {
+                    stage.reach = u64::MAX;
+                }
+                assert_eq!(
+                    run(stage, &sites).0,
+                    greedy(max, window, mode, &sites),
+                    "max {}, window {}, {:?}, sites {:?}",
+                    max,
+                    window,
+                    mode,
+                    sites
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_density_cap_reach() {
+        // ranks improving towards the right would chain the wait to the end
+        let sites: Vec<(u64, usize)> = (0..200).map(|i| (i * 3 + 1, 1000 - i as usize)).collect();
+        let (kept, buffered) = run(DensityCap::new(1, 10, WindowMode::Sliding), &sites);
+        assert!(buffered <= 10, "buffered {} records", buffered);
+        assert!(
+            kept.windows(2).all(|w| w[1] - w[0] >= 10),
+            "kept {:?}",
+            kept
+        );
+        // 1 is decided out of reach before the better-ranked 4 and 7
+        assert_eq!(kept[0], 1);
+        assert_eq!(kept.last(), Some(&598));
+    }
+}
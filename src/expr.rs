@@ -0,0 +1,505 @@
+use vcf::VCFRecord;
+
+use crate::vcf_util::{filter_values, is_symbolic};
+
+/// Variables of a record available in expressions.
+#[derive(Debug, Clone, PartialEq)]
+enum Var {
+    /// FORGe rank
+    Forge,
+    Chrom,
+    Pos,
+    Qual,
+    Filter,
+    /// Variant types of ALT alleles: snp, mnp, indel, sv, or ref
+    Type,
+    /// Length of REF allele
+    RefLen,
+    /// Lengths of non-symbolic ALT alleles
+    AltLen,
+    /// Length differences of non-symbolic ALT alleles from REF allele
+    ILen,
+    Info(String),
+}
+
+impl Var {
+    fn parse(name: &str) -> Option<Self> {
+        if let Some(key) = name.strip_prefix("INFO/") {
+            return Some(Var::Info(key.to_string()));
+        }
+        match name {
+            "FORGE" => Some(Var::Forge),
+            "CHROM" => Some(Var::Chrom),
+            "POS" => Some(Var::Pos),
+            "QUAL" => Some(Var::Qual),
+            "FILTER" => Some(Var::Filter),
+            "TYPE" => Some(Var::Type),
+            "REFLEN" => Some(Var::RefLen),
+            "ALTLEN" => Some(Var::AltLen),
+            "ILEN" => Some(Var::ILen),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Num(f64),
+    Str(String),
+    Var(Var),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Or(Box<Node>, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Cmp(Operand, CmpOp, Operand),
+    /// Bare operand; true if it has any non-zero value, e.g. an INFO flag
+    Test(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Num(f64),
+    Str(String),
+    Op(&'static str),
+}
+
+/// Split an expression into tokens.
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    const OPS: [&str; 13] = [
+        "||", "&&", "==", "!=", "<=", ">=", "<", ">", "=", "!", "(", ")", "-",
+    ];
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() || c == '.' {
+            let end = rest
+                .char_indices()
+                .find(|(i, c)| {
+                    !(c.is_ascii_alphanumeric()
+                        || *c == '.'
+                        || ((*c == '-' || *c == '+') && rest[..*i].ends_with(['e', 'E'])))
+                })
+                .map_or(rest.len(), |(i, _)| i);
+            let value = rest[..end]
+                .parse::<f64>()
+                .map_err(|_| format!("invalid number '{}'", &rest[..end]))?;
+            tokens.push(Token::Num(value));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '/'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..]
+                .find(c)
+                .ok_or_else(|| "unterminated string".to_string())?;
+            tokens.push(Token::Str(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else {
+            let op = OPS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| format!("unexpected character '{}'", c))?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser of expressions.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn accept(&mut self, op: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Op(o)) if *o == op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+        while self.accept("||") {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while self.accept("&&") {
+            node = Node::And(Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.accept("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.accept("(") {
+            let node = self.or()?;
+            if !self.accept(")") {
+                return Err("missing ')'".to_string());
+            }
+            return Ok(node);
+        }
+        let left = self.operand()?;
+        let op = match self.peek() {
+            Some(Token::Op("==")) | Some(Token::Op("=")) => CmpOp::Eq,
+            Some(Token::Op("!=")) => CmpOp::Ne,
+            Some(Token::Op("<")) => CmpOp::Lt,
+            Some(Token::Op("<=")) => CmpOp::Le,
+            Some(Token::Op(">")) => CmpOp::Gt,
+            Some(Token::Op(">=")) => CmpOp::Ge,
+            _ => return Ok(Node::Test(left)),
+        };
+        self.pos += 1;
+        Ok(Node::Cmp(left, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let negative = self.accept("-");
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(value)) if negative => Ok(Operand::Num(-value)),
+            Some(Token::Num(value)) => Ok(Operand::Num(value)),
+            Some(Token::Str(value)) if !negative => Ok(Operand::Str(value)),
+            Some(Token::Ident(name)) if !negative => Var::parse(&name)
+                .map(Operand::Var)
+                .ok_or_else(|| format!("unknown variable '{}'", name)),
+            Some(token) => Err(format!("unexpected token {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+/// Value of an operand evaluated on a record.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Num(f64),
+    Str(Vec<u8>),
+}
+
+impl Value {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+        {
+            Some(value) => Value::Num(value),
+            None => Value::Str(bytes.to_vec()),
+        }
+    }
+
+    fn compare(&self, op: CmpOp, other: &Value) -> bool {
+        let ordering = match (self, other) {
+            (Value::Num(a), Value::Num(b)) => a.partial_cmp(b),
+            (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+            _ => return op == CmpOp::Ne,
+        };
+        let ordering = match ordering {
+            Some(ordering) => ordering,
+            None => return false,
+        };
+        match op {
+            CmpOp::Eq => ordering.is_eq(),
+            CmpOp::Ne => ordering.is_ne(),
+            CmpOp::Lt => ordering.is_lt(),
+            CmpOp::Le => ordering.is_le(),
+            CmpOp::Gt => ordering.is_gt(),
+            CmpOp::Ge => ordering.is_ge(),
+        }
+    }
+
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Num(value) => *value != 0.0,
+            Value::Str(_) => true,
+        }
+    }
+}
+
+/// Get the variant type of an ALT allele.
+fn allele_type(reference: &[u8], allele: &[u8]) -> &'static [u8] {
+    if is_symbolic(allele) {
+        b"sv"
+    } else if allele == b"." || allele == b"*" || allele == reference {
+        b"ref"
+    } else if allele.len() != reference.len() {
+        b"indel"
+    } else if allele.len() == 1 {
+        b"snp"
+    } else {
+        b"mnp"
+    }
+}
+
+/// A parsed expression for selecting records.
+///
+/// Expressions combine comparisons by `&&`, `||`, `!`, and parentheses; e.g.
+/// `FORGE <= 50000 && INFO/AF > 0.01 && TYPE == "snp"`. Variables with
+/// multiple values, such as `INFO/AF` of a multi-allelic record, satisfy a
+/// comparison if any of their values does. Missing values satisfy no
+/// comparison but `!=`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl std::str::FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let root = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected token {:?}", token));
+        }
+        Ok(Expression {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Expression {
+    /// Evaluate the expression on a record with its FORGe rank, if ranked.
+    pub fn eval(&self, record: &VCFRecord, rank: Option<usize>) -> bool {
+        eval_node(&self.root, record, rank)
+    }
+}
+
+fn eval_node(node: &Node, record: &VCFRecord, rank: Option<usize>) -> bool {
+    match node {
+        Node::Or(left, right) => eval_node(left, record, rank) || eval_node(right, record, rank),
+        Node::And(left, right) => eval_node(left, record, rank) && eval_node(right, record, rank),
+        Node::Not(node) => !eval_node(node, record, rank),
+        Node::Cmp(left, op, right) => {
+            let left = values(left, record, rank);
+            let right = values(right, record, rank);
+            if left.is_empty() || right.is_empty() {
+                return *op == CmpOp::Ne;
+            }
+            left.iter().any(|l| right.iter().any(|r| l.compare(*op, r)))
+        }
+        Node::Test(Operand::Var(Var::Info(key))) => record.info(key.as_bytes()).is_some(),
+        Node::Test(operand) => values(operand, record, rank).iter().any(Value::is_true),
+    }
+}
+
+/// Evaluate an operand on a record.
+fn values(operand: &Operand, record: &VCFRecord, rank: Option<usize>) -> Vec<Value> {
+    let var = match operand {
+        Operand::Num(value) => return vec![Value::Num(*value)],
+        Operand::Str(value) => return vec![Value::Str(value.as_bytes().to_vec())],
+        Operand::Var(var) => var,
+    };
+    let alleles = || {
+        record
+            .alternative
+            .iter()
+            .filter(|a| !is_symbolic(a) && *a != b"." && *a != b"*")
+    };
+    match var {
+        Var::Forge => rank.map(|r| Value::Num(r as f64)).into_iter().collect(),
+        Var::Chrom => vec![Value::Str(record.chromosome.clone())],
+        Var::Pos => vec![Value::Num(record.position as f64)],
+        Var::Qual => record.qual.map(Value::Num).into_iter().collect(),
+        Var::Filter if record.filter.is_empty() => vec![Value::Str(b".".to_vec())],
+        Var::Filter => filter_values(record)
+            .map(|f| Value::Str(f.to_vec()))
+            .collect(),
+        Var::Type => record
+            .alternative
+            .iter()
+            .map(|a| Value::Str(allele_type(&record.reference, a).to_vec()))
+            .collect(),
+        Var::RefLen => vec![Value::Num(record.reference.len() as f64)],
+        Var::AltLen => alleles().map(|a| Value::Num(a.len() as f64)).collect(),
+        Var::ILen => alleles()
+            .map(|a| Value::Num(a.len() as f64 - record.reference.len() as f64))
+            .collect(),
+        Var::Info(key) => record
+            .info(key.as_bytes())
+            .map(|v| {
+                v.iter()
+                    .filter(|v| v.as_slice() != b".")
+                    .map(|v| Value::from_bytes(v))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::parse_vcf;
+
+    const VCF: &str = "##fileformat=VCFv4.2
+##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency\">
+##INFO=<ID=DB,Number=0,Type=Flag,Description=\"dbSNP\">
+##INFO=<ID=SRC,Number=1,Type=String,Description=\"Source\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t100\t.\tA\tC\t50\tPASS\tAF=0.2;DB;SRC=gnomad
+chr1\t200\t.\tAT\tA,ATT,<DEL>\t.\tq10;lowDP\tAF=0.001,.,0.5
+chr2\t300\t.\tAC\tGT\t10\t.\t.
+";
+
+    fn records() -> Vec<VCFRecord> {
+        parse_vcf(VCF).1
+    }
+
+    fn eval(expr: &str, record: &VCFRecord, rank: Option<usize>) -> bool {
+        expr.parse::<Expression>().unwrap().eval(record, rank)
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("INFO/AF>=1e-3&&!(TYPE=='snp')").unwrap(),
+            vec![
+                Token::Ident("INFO/AF".to_string()),
+                Token::Op(">="),
+                Token::Num(1e-3),
+                Token::Op("&&"),
+                Token::Op("!"),
+                Token::Op("("),
+                Token::Ident("TYPE".to_string()),
+                Token::Op("=="),
+                Token::Str("snp".to_string()),
+                Token::Op(")"),
+            ]
+        );
+        assert!(tokenize("POS > 1.2.3").is_err());
+        assert!(tokenize("CHROM == \"chr1").is_err());
+        assert!(tokenize("POS # 1").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let expr: Expression = "FORGE <= 10 || POS > 5 && !INFO/DB".parse().unwrap();
+        // && binds tighter than ||
+        assert_eq!(
+            expr.root,
+            Node::Or(
+                Box::new(Node::Cmp(
+                    Operand::Var(Var::Forge),
+                    CmpOp::Le,
+                    Operand::Num(10.0)
+                )),
+                Box::new(Node::And(
+                    Box::new(Node::Cmp(
+                        Operand::Var(Var::Pos),
+                        CmpOp::Gt,
+                        Operand::Num(5.0)
+                    )),
+                    Box::new(Node::Not(Box::new(Node::Test(Operand::Var(Var::Info(
+                        "DB".to_string()
+                    )))))),
+                ))
+            )
+        );
+        assert_eq!(expr.to_string(), "FORGE <= 10 || POS > 5 && !INFO/DB");
+        assert_eq!(
+            "ILEN < -2".parse::<Expression>().unwrap().root,
+            Node::Cmp(Operand::Var(Var::ILen), CmpOp::Lt, Operand::Num(-2.0))
+        );
+        for invalid in [
+            "", "POS >", "(POS > 1", "POS > 1)", "FOO > 1", "-POS > 1", "POS 1",
+        ] {
+            assert!(invalid.parse::<Expression>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_eval_fields() {
+        let records = records();
+        let snp = &records[0];
+        assert!(eval("CHROM == 'chr1' && POS == 100", snp, None));
+        assert!(eval("QUAL >= 50 && FILTER = 'PASS'", snp, None));
+        assert!(eval(
+            "TYPE == \"snp\" && REFLEN == 1 && ALTLEN == 1",
+            snp,
+            None
+        ));
+        assert!(eval("INFO/SRC == 'gnomad' && INFO/DB", snp, None));
+        assert!(eval("FORGE <= 10", snp, Some(10)));
+        assert!(!eval("FORGE < 10", snp, Some(10)));
+        assert!(eval("TYPE == 'mnp'", &records[2], None));
+        assert!(eval("FILTER == '.'", &records[2], None));
+    }
+
+    #[test]
+    fn test_eval_multiple_values() {
+        let records = records();
+        let multi = &records[1];
+        // any value satisfies a comparison
+        assert!(eval("INFO/AF > 0.1", multi, None));
+        assert!(eval("INFO/AF < 0.01", multi, None));
+        assert!(!eval("INFO/AF > 0.6", multi, None));
+        assert!(eval("TYPE == 'indel' && TYPE == 'sv'", multi, None));
+        assert!(!eval("TYPE == 'snp'", multi, None));
+        assert!(eval("FILTER == 'lowDP'", multi, None));
+        // symbolic alleles have no length
+        assert!(eval("ILEN == -1 && ILEN == 1", multi, None));
+        assert!(!eval("ALTLEN > 3", multi, None));
+    }
+
+    #[test]
+    fn test_eval_missing_values() {
+        let records = records();
+        let multi = &records[1];
+        // missing values satisfy no comparison but `!=`
+        assert!(!eval("QUAL > 0", multi, None));
+        assert!(!eval("QUAL <= 0", multi, None));
+        assert!(eval("QUAL != 0", multi, None));
+        assert!(!eval("FORGE <= 10", multi, None));
+        assert!(eval("FORGE != 10", multi, None));
+        assert!(!eval("INFO/XX == 1", multi, None));
+        assert!(eval("!(INFO/XX == 1)", multi, None));
+        assert!(!eval("INFO/DB", multi, None));
+        assert!(!eval("INFO/AF", &records[2], None));
+        // a number never equals a string
+        assert!(!eval("CHROM == 1", multi, None));
+        assert!(eval("CHROM != 1", multi, None));
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::vcf_util::is_gzipped_file;
+
+/// Entry of a FASTA index (.fai).
+struct FaiEntry {
+    /// Length of the sequence
+    length: u64,
+    /// Byte offset of the first base of the sequence in the file
+    offset: u64,
+    /// Number of bases per line
+    line_bases: u64,
+    /// Number of bytes per line including the line terminator
+    line_width: u64,
+}
+
+/// Reference sequences of an uncompressed FASTA file accessed randomly.
+///
+/// The FASTA index (.fai) next to the file is used if present; otherwise, the
+/// file is scanned once to build the index in memory. Lines of a sequence must
+/// have the same length except for the last one.
+pub struct Fasta {
+    path: PathBuf,
+    reader: BufReader<File>,
+    index: HashMap<Vec<u8>, FaiEntry>,
+}
+
+impl Fasta {
+    /// Open a FASTA file.
+    pub fn open<T>(path: &T) -> Result<Self, Error>
+    where
+        T: AsRef<Path>,
+    {
+        if is_gzipped_file(path)? {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "compressed FASTA files are not supported",
+            ));
+        }
+        let mut fai_path = path.as_ref().as_os_str().to_owned();
+        fai_path.push(".fai");
+        let index = match File::open(&fai_path) {
+            Ok(file) => load_fai(file)?,
+            Err(_) => build_fai(File::open(path)?)?,
+        };
+        Ok(Fasta {
+            path: path.as_ref().to_path_buf(),
+            reader: BufReader::new(File::open(path)?),
+            index,
+        })
+    }
+
+    /// Get the path of the FASTA file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Fetch the bases in a range of a sequence in upper case.
+    ///
+    /// # Arguments
+    ///
+    /// * `chrom` - Sequence name
+    /// * `start` - Start position, 1-based
+    /// * `end` - End position, 1-based and inclusive
+    pub fn fetch(&mut self, chrom: &[u8], start: u64, end: u64) -> Result<Vec<u8>, Error> {
+        let entry = self.index.get(chrom).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "sequence '{}' not found in the reference",
+                    String::from_utf8_lossy(chrom)
+                ),
+            )
+        })?;
+        if start == 0 || end < start || end > entry.length {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "range {}:{}-{} out of the reference sequence of length {}",
+                    String::from_utf8_lossy(chrom),
+                    start,
+                    end,
+                    entry.length
+                ),
+            ));
+        }
+        let byte_offset = |pos: u64| {
+            entry.offset + pos / entry.line_bases * entry.line_width + pos % entry.line_bases
+        };
+        let first = byte_offset(start - 1);
+        let last = byte_offset(end - 1);
+        let mut bytes = vec![0; (last - first + 1) as usize];
+        self.reader.seek(SeekFrom::Start(first))?;
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes
+            .into_iter()
+            .filter(|b| !b.is_ascii_whitespace())
+            .map(|b| b.to_ascii_uppercase())
+            .collect())
+    }
+}
+
+/// Parse a FASTA index (.fai).
+fn load_fai(file: File) -> Result<HashMap<Vec<u8>, FaiEntry>, Error> {
+    let mut index = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        let numbers: Option<Vec<u64>> = fields
+            .get(1..5)
+            .map(|f| f.iter().filter_map(|v| v.parse().ok()).collect());
+        match numbers.as_deref() {
+            Some([length, offset, line_bases, line_width]) => {
+                index.insert(
+                    fields[0].as_bytes().to_vec(),
+                    FaiEntry {
+                        length: *length,
+                        offset: *offset,
+                        line_bases: *line_bases,
+                        line_width: *line_width,
+                    },
+                );
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid FASTA index line: '{}'", line),
+                ))
+            }
+        }
+    }
+    Ok(index)
+}
+
+/// Build the index of a FASTA file by scanning it.
+fn build_fai(file: File) -> Result<HashMap<Vec<u8>, FaiEntry>, Error> {
+    let mut index = HashMap::new();
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    let mut offset = 0;
+    let mut current: Option<(Vec<u8>, FaiEntry)> = None;
+    loop {
+        line.clear();
+        let width = reader.read_until(b'\n', &mut line)? as u64;
+        if width == 0 {
+            break;
+        }
+        offset += width;
+        if line[0] == b'>' {
+            if let Some((name, entry)) = current.take() {
+                index.insert(name, entry);
+            }
+            let name = line[1..]
+                .split(|b| b.is_ascii_whitespace())
+                .next()
+                .unwrap_or_default()
+                .to_vec();
+            let entry = FaiEntry {
+                length: 0,
+                offset,
+                line_bases: 0,
+                line_width: 0,
+            };
+            current = Some((name, entry));
+        } else if let Some((_, entry)) = current.as_mut() {
+            let bases = line.iter().filter(|b| !b.is_ascii_whitespace()).count() as u64;
+            if entry.line_bases == 0 {
+                entry.line_bases = bases;
+                entry.line_width = width;
+            }
+            entry.length += bases;
+        }
+    }
+    if let Some((name, entry)) = current {
+        index.insert(name, entry);
+    }
+    Ok(index)
+}
+
+/// FASTA file written into the temporary directory and removed on drop; for
+/// tests.
+#[cfg(test)]
+pub struct TempFasta(pub PathBuf);
+
+#[cfg(test)]
+impl TempFasta {
+    pub fn new(name: &str, text: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("forgers-test-{}-{}.fa", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        TempFasta(path)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFasta {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -1,52 +1,776 @@
-use std::io::{BufReader, BufWriter, Read, Write};
+use log::info;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::iter::zip;
 use std::path::Path;
-use vcf::{VCFError, VCFReader, VCFRecord, VCFWriter};
+use vcf::{VCFError, VCFHeader, VCFRecord, VCFWriter};
 
-use crate::forge;
+use crate::density::DensityCap;
+use crate::forge::{self, Top};
+use crate::group::{Grouper, Grouping};
+use crate::haplotype::HaplotypeCap;
+use crate::option::{AnnotateOpt, FilterOpt, Order, TopBasis};
+use crate::screen::{Screen, Screening};
+use crate::sort::{BufferReader, RankSorter, RecordBuffer};
+use crate::vcf_util::{
+    extend_header, info_header_line, variant_class, DynWriter, Emitted, RecordRead, RecordStage,
+    RecordWrite, Staged, VariantClass,
+};
+
+/// Get the output VCF header with definitions of the requested annotations.
+pub fn output_header(header: &VCFHeader, opts: &FilterOpt) -> VCFHeader {
+    let annotation = &opts.annotation;
+    let mut lines = Vec::new();
+    if annotation.annotate {
+        lines.push((&annotation.info_key, "Integer", "FORGe rank"));
+    }
+    if annotation.annotate_percentile {
+        lines.push((
+            &annotation.percentile_key,
+            "Float",
+            "FORGe rank divided by the total number of entries in the rank file",
+        ));
+    }
+    if annotation.annotate_bin {
+        lines.push((
+            &annotation.bin_key,
+            "Float",
+            "Smallest top fraction bin containing the FORGe rank",
+        ));
+    }
+    if annotation.annotate_score {
+        lines.push((&annotation.score_key, "Float", "FORGe score"));
+    }
+    if opts.group_by.is_some() {
+        lines.push((
+            &opts.group_key,
+            "Integer",
+            "ID of the group of linked records kept or dropped together",
+        ));
+    }
+    let lines = lines
+        .into_iter()
+        .filter(|(key, _, _)| header.info(key.as_bytes()).is_none())
+        .map(|(key, value_type, description)| info_header_line(key, "1", value_type, description))
+        .collect();
+    extend_header(header, lines)
+}
+
+/// Annotate a VCF record with the requested FORGe annotations.
+///
+/// # Arguments
+///
+/// * `vcf_record` - VCF record to be annotated
+/// * `rank` - FORGe rank of the record
+/// * `ranking` - FORGe ranking
+/// * `annotation` - Annotation options
+fn annotate(
+    vcf_record: &mut VCFRecord,
+    rank: usize,
+    ranking: &forge::Ranking,
+    annotation: &AnnotateOpt,
+) {
+    let percentile = forge::percentile(rank, ranking.total);
+    if annotation.annotate {
+        vcf_record.insert_info(
+            annotation.info_key.as_bytes(),
+            vec![format!("{}", rank).as_bytes().to_vec()],
+        );
+    }
+    if annotation.annotate_percentile {
+        vcf_record.insert_info(
+            annotation.percentile_key.as_bytes(),
+            vec![format!("{:.6}", percentile).as_bytes().to_vec()],
+        );
+    }
+    if annotation.annotate_bin {
+        if let Some(bin) = forge::fraction_bin(percentile, &annotation.bins.0) {
+            vcf_record.insert_info(
+                annotation.bin_key.as_bytes(),
+                vec![format!("{}", bin).as_bytes().to_vec()],
+            );
+        }
+    }
+    if annotation.annotate_score {
+        if let Some(score) = forge::forge_score(vcf_record, &ranking.scores) {
+            let value = format!("{}", score).as_bytes().to_vec();
+            vcf_record.insert_info(annotation.score_key.as_bytes(), vec![value]);
+        }
+    }
+}
+
+/// Annotate a VCF record with the ID of its group of linked records, if any.
+fn annotate_group(vcf_record: &mut VCFRecord, group_id: Option<usize>, opts: &FilterOpt) {
+    if let Some(id) = group_id {
+        vcf_record.insert_info(
+            opts.group_key.as_bytes(),
+            vec![format!("{}", id).as_bytes().to_vec()],
+        );
+    }
+}
+
+/// Check whether a record is selected by the include and exclude expressions.
+fn is_selected(record: &VCFRecord, rank: Option<usize>, opts: &FilterOpt) -> bool {
+    opts.include.as_ref().is_none_or(|e| e.eval(record, rank))
+        && !opts.exclude.as_ref().is_some_and(|e| e.eval(record, rank))
+}
+
+/// Number of variant classes
+const NOF_CLASSES: usize = VariantClass::ALL.len();
+
+/// Output stream of the records filtered out.
+///
+/// A record passing the cutoffs is rejected once removed by the stages of
+/// every output whose cutoff it meets; so that the rejected records are the
+/// ones written into no output.
+struct Rejected {
+    writer: Option<DynWriter>,
+    /// Number of outputs yet to remove each staged record, by input index
+    pending: HashMap<usize, usize>,
+}
+
+impl Rejected {
+    fn new(writer: Option<DynWriter>) -> Self {
+        Rejected {
+            writer,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Write a record filtered out before the stages.
+    fn write(&mut self, record: &VCFRecord) -> Result<(), VCFError> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.write_record(record),
+            None => Ok(()),
+        }
+    }
+
+    /// Expect a record to be decided by the stages of a number of outputs.
+    fn expect(&mut self, idx: usize, nof_outputs: usize) {
+        if self.writer.is_some() && nof_outputs > 0 {
+            self.pending.insert(idx, nof_outputs);
+        }
+    }
+
+    /// Note that a record is kept by the stages of an output.
+    fn kept(&mut self, idx: usize) {
+        self.pending.remove(&idx);
+    }
+
+    /// Note that a record is removed by the stages of an output, and write it
+    /// if it is removed from all of them.
+    fn removed(&mut self, staged: &Staged) -> Result<(), VCFError> {
+        if let Some(left) = self.pending.get_mut(&staged.idx) {
+            *left -= 1;
+            if *left == 0 {
+                self.pending.remove(&staged.idx);
+                return self.write(&staged.record);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output stream of the records among a number of top records.
+struct Output<'a> {
+    top: Top,
+    /// Highest FORGe rank to keep for each variant class
+    cutoffs: [usize; NOF_CLASSES],
+    writer: &'a mut dyn RecordWrite,
+    sorter: Option<RankSorter>,
+    /// Streaming stages removing records after the cutoffs, e.g. density caps
+    stages: Vec<Box<dyn RecordStage>>,
+    /// Number of records written for each variant class
+    counts: [usize; NOF_CLASSES],
+}
+
+impl Output<'_> {
+    /// Check whether a rank is within the cutoff of a class.
+    fn meets(&self, rank: usize, class: VariantClass) -> bool {
+        rank <= self.cutoffs[class.index()]
+    }
+
+    /// Write the record if its rank is within the cutoff of its class.
+    fn write(
+        &mut self,
+        idx: usize,
+        rank: usize,
+        class: VariantClass,
+        record: &VCFRecord,
+        rejected: &mut Rejected,
+    ) -> Result<(), VCFError> {
+        if !self.meets(rank, class) {
+            return Ok(());
+        }
+        self.emit(idx, rank, Some(class), record, rejected)
+    }
+
+    /// Write the record regardless of the cutoffs and the stages.
+    fn pass(
+        &mut self,
+        idx: usize,
+        rank: usize,
+        record: &VCFRecord,
+        rejected: &mut Rejected,
+    ) -> Result<(), VCFError> {
+        self.emit(idx, rank, None, record, rejected)
+    }
+
+    /// Write the record through the stages, if any.
+    fn emit(
+        &mut self,
+        idx: usize,
+        rank: usize,
+        class: Option<VariantClass>,
+        record: &VCFRecord,
+        rejected: &mut Rejected,
+    ) -> Result<(), VCFError> {
+        if self.stages.is_empty() {
+            return self.sink(rank, class, record);
+        }
+        let mut staged = vec![Staged {
+            idx,
+            rank,
+            class,
+            record: record.clone(),
+        }];
+        for stage in self.stages.iter_mut() {
+            let mut emitted = Emitted::default();
+            for s in staged {
+                stage.push(s, &mut emitted);
+            }
+            for s in emitted.removed.iter() {
+                rejected.removed(s)?;
+            }
+            staged = emitted.kept;
+        }
+        for s in staged {
+            rejected.kept(s.idx);
+            self.sink(s.rank, s.class, &s.record)?;
+        }
+        Ok(())
+    }
+
+    /// Write the record into the sorter, if any, or the output stream.
+    fn sink(
+        &mut self,
+        rank: usize,
+        class: Option<VariantClass>,
+        record: &VCFRecord,
+    ) -> Result<(), VCFError> {
+        if let Some(class) = class {
+            self.counts[class.index()] += 1;
+        }
+        match self.sorter.as_mut() {
+            Some(sorter) => sorter.push(rank, record.clone()),
+            None => self.writer.write(record),
+        }
+    }
+
+    /// Flush the records buffered by the stages and the sorter, if any, into
+    /// the output stream.
+    fn finish(mut self, rejected: &mut Rejected) -> Result<(), VCFError> {
+        let mut staged = Vec::new();
+        for stage in self.stages.iter_mut() {
+            let mut emitted = Emitted::default();
+            for s in staged {
+                stage.push(s, &mut emitted);
+            }
+            stage.flush(&mut emitted);
+            for s in emitted.removed.iter() {
+                rejected.removed(s)?;
+            }
+            staged = emitted.kept;
+            info!("{} for top {}", stage.summary(), self.top);
+        }
+        for s in staged {
+            rejected.kept(s.idx);
+            self.sink(s.rank, s.class, &s.record)?;
+        }
+        for class in VariantClass::ALL {
+            info!(
+                "Wrote {} {} records for top {}",
+                self.counts[class.index()],
+                class.name(),
+                self.top
+            );
+        }
+        match self.sorter {
+            Some(sorter) => sorter.finish(self.writer),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write a ranked record into every output whose cutoff it meets.
+///
+/// Only the outputs with stages can remove it, so the rejected output waits
+/// for the decisions of those.
+fn write_ranked(
+    outputs: &mut [Output],
+    idx: usize,
+    rank: usize,
+    class: VariantClass,
+    record: &VCFRecord,
+    rejected: &mut Rejected,
+) -> Result<(), VCFError> {
+    let nof_staged = outputs
+        .iter()
+        .filter(|o| !o.stages.is_empty() && o.meets(rank, class))
+        .count();
+    rejected.expect(idx, nof_staged);
+    for output in outputs.iter_mut() {
+        output.write(idx, rank, class, record, rejected)?;
+    }
+    Ok(())
+}
+
+/// Make the stages removing records of each output after the cutoffs.
+fn stages(opts: &FilterOpt) -> Vec<Box<dyn RecordStage>> {
+    let mut stages: Vec<Box<dyn RecordStage>> = Vec::new();
+    if let Some(max) = opts.max_per_window {
+        stages.push(Box::new(DensityCap::new(
+            max,
+            opts.window,
+            opts.window_mode,
+        )));
+    }
+    if let Some(max) = opts.max_haplotypes {
+        stages.push(Box::new(HaplotypeCap::new(max, opts.haplotype_window)));
+    }
+    stages
+}
+
+/// Sorted FORGe ranks of the ranked records in the input.
+struct InputRanks {
+    all: Vec<usize>,
+    by_class: [Vec<usize>; NOF_CLASSES],
+}
+
+/// Groups of linked records with the variant class of each group.
+struct Groups {
+    grouping: Grouping,
+    /// Class of the best-ranked record of the group of each record; its own
+    /// class if none is ranked
+    classes: Vec<VariantClass>,
+}
+
+/// Buffer the input records and collect the ranks of the ranked ones.
+///
+/// It returns the sorted FORGe ranks of the ranked records in the input, the
+/// groups of linked records if requested, and a reader of the buffered
+/// records. The records in a group are ranked by the best rank among them and
+/// classed by the class of the best-ranked one.
+fn buffer_input<S, T>(
+    vcf_reader: &mut S,
+    ranking: &forge::Ranking,
+    opts: &FilterOpt,
+    screen: &Screen,
+    tmpdir: &T,
+) -> Result<(InputRanks, Option<Groups>, BufferReader), VCFError>
+where
+    S: RecordRead,
+    T: AsRef<Path>,
+{
+    let mut buffer = RecordBuffer::new(vcf_reader.header(), tmpdir)?;
+    let mut ranks = InputRanks {
+        all: Vec::new(),
+        by_class: Default::default(),
+    };
+    let mut grouper = opts
+        .group_by
+        .as_ref()
+        .map(|by| Grouper::new(by.clone(), opts.group_window));
+    // ranks and classes of the records and the indices of the competing ones
+    let mut own_ranks = Vec::new();
+    let mut classes = Vec::new();
+    let mut competing = Vec::new();
+    let mut vcf_record = VCFRecord::new(vcf_reader.header().clone());
+    while vcf_reader.next_record(&mut vcf_record)? {
+        let rank = forge::forge_rank(&vcf_record, &ranking.sites).copied();
+        let selected =
+            screen.check(&vcf_record) == Screening::Pass && is_selected(&vcf_record, rank, opts);
+        let class = variant_class(&vcf_record, opts.sv_min_len);
+        match grouper.as_mut() {
+            Some(grouper) => {
+                grouper.push(&vcf_record);
+                if selected {
+                    competing.push(own_ranks.len());
+                }
+                own_ranks.push(rank.filter(|_| selected));
+                classes.push(class);
+            }
+            None => {
+                if let Some(fr) = rank.filter(|_| selected) {
+                    ranks.all.push(fr);
+                    ranks.by_class[class.index()].push(fr);
+                }
+            }
+        }
+        buffer.push(&vcf_record)?;
+    }
+    let groups = grouper.map(|grouper| {
+        let grouping = grouper.finish(&own_ranks);
+        let classes = zip(&grouping.best, &classes)
+            .map(|(best, class)| best.map_or(*class, |b| classes[b]))
+            .collect();
+        Groups { grouping, classes }
+    });
+    if let Some(Groups { grouping, classes }) = groups.as_ref() {
+        for idx in competing {
+            if let Some(fr) = grouping.ranks[idx] {
+                ranks.all.push(fr);
+                ranks.by_class[classes[idx].index()].push(fr);
+            }
+        }
+        info!(
+            "Linked {} records into {} groups",
+            grouping.ids.iter().filter(|id| id.is_some()).count(),
+            grouping.nof_groups
+        );
+    }
+    ranks.all.sort_unstable();
+    info!("Found {} ranked records in the input", ranks.all.len());
+    for class in VariantClass::ALL {
+        let order = &mut ranks.by_class[class.index()];
+        order.sort_unstable();
+        info!("  of which {} are {}", order.len(), class.name());
+    }
+    Ok((ranks, groups, buffer.into_reader()?))
+}
+
+/// Write each record into the output streams whose cutoff it meets.
+///
+/// The records in a group of linked records, if given, meet the cutoffs by
+/// the best rank and the class of their group.
+fn write_records<S>(
+    vcf_reader: &mut S,
+    outputs: &mut [Output],
+    ranking: &forge::Ranking,
+    opts: &FilterOpt,
+    screen: &Screen,
+    groups: Option<&Groups>,
+    rejected: &mut Rejected,
+) -> Result<(), VCFError>
+where
+    S: RecordRead,
+{
+    let mut forced_in = 0;
+    let mut forced_out = 0;
+    let mut failed = 0;
+    let mut dropped = 0;
+    let mut soft_filtered = 0;
+    let mut unselected = 0;
+    let mut max_cutoffs = [0; NOF_CLASSES];
+    for output in outputs.iter() {
+        for (max_cutoff, cutoff) in zip(&mut max_cutoffs, output.cutoffs) {
+            *max_cutoff = std::cmp::max(*max_cutoff, cutoff);
+        }
+    }
+    let mut input_counts = [0; NOF_CLASSES];
+    let mut vcf_record = VCFRecord::new(vcf_reader.header().clone());
+    let mut idx = 0;
+    loop {
+        let fetched = vcf_reader.next_record(&mut vcf_record)?;
+        if fetched {
+            let class = variant_class(&vcf_record, opts.sv_min_len);
+            input_counts[class.index()] += 1;
+            let group = groups.map(|g| (g.grouping.ranks[idx], g.grouping.ids[idx]));
+            let group_class = groups.map_or(class, |g| g.classes[idx]);
+            let record_idx = idx;
+            idx += 1;
+            let group_id = group.and_then(|(_, id)| id);
+            match screen.check(&vcf_record) {
+                Screening::Pass => {}
+                Screening::ForcedIn => {
+                    forced_in += 1;
+                    let rank = forge::forge_rank(&vcf_record, &ranking.sites).copied();
+                    if let Some(fr) = rank {
+                        annotate(&mut vcf_record, fr, ranking, &opts.annotation);
+                    }
+                    annotate_group(&mut vcf_record, group_id, opts);
+                    for output in outputs.iter_mut() {
+                        output.pass(
+                            record_idx,
+                            rank.unwrap_or(usize::MAX),
+                            &vcf_record,
+                            rejected,
+                        )?;
+                    }
+                    continue;
+                }
+                Screening::SoftFiltered => {
+                    soft_filtered += 1;
+                    screen.soft_filter(&mut vcf_record);
+                    annotate_group(&mut vcf_record, group_id, opts);
+                    for output in outputs.iter_mut() {
+                        output.pass(record_idx, usize::MAX, &vcf_record, rejected)?;
+                    }
+                    continue;
+                }
+                screening @ (Screening::ForcedOut
+                | Screening::Failed
+                | Screening::Unranked
+                | Screening::Oversized) => {
+                    match screening {
+                        Screening::ForcedOut => forced_out += 1,
+                        Screening::Oversized => dropped += 1,
+                        _ => failed += 1,
+                    }
+                    rejected.write(&vcf_record)?;
+                    continue;
+                }
+            }
+            let rank = forge::forge_rank(&vcf_record, &ranking.sites).copied();
+            if !is_selected(&vcf_record, rank, opts) {
+                unselected += 1;
+                rejected.write(&vcf_record)?;
+                continue;
+            }
+            match group.map_or(rank, |(group_rank, _)| group_rank) {
+                Some(fr) if fr <= max_cutoffs[group_class.index()] => {
+                    if let Some(own) = rank {
+                        annotate(&mut vcf_record, own, ranking, &opts.annotation);
+                    }
+                    annotate_group(&mut vcf_record, group_id, opts);
+                    write_ranked(outputs, record_idx, fr, group_class, &vcf_record, rejected)?;
+                }
+                _ => rejected.write(&vcf_record)?,
+            }
+        } else {
+            break;
+        }
+    }
+    for class in VariantClass::ALL {
+        info!(
+            "Read {} {} records",
+            input_counts[class.index()],
+            class.name()
+        );
+    }
+    info!(
+        "Forced {} records to be included and {} to be excluded",
+        forced_in, forced_out
+    );
+    info!("Dropped {} records failing the FILTER check", failed);
+    info!(
+        "Dropped {} and soft-filtered {} records exceeding the allele length caps",
+        dropped, soft_filtered
+    );
+    info!(
+        "Dropped {} records by the include/exclude expressions",
+        unselected
+    );
+    Ok(())
+}
 
 /// Filter and annotate VCF records based on FORGe ranking.
 ///
+/// The input is read once and each record is written into the output streams
+/// of all top values whose cutoff it meets. When top fractions refer to the
+/// input records, the input is buffered in a temporary file first to count
+/// the ranked records.
+///
+/// The density of the records in each output can be capped to a number of
+/// records per genomic window; the best-ranked records are kept in each window.
+/// Likewise, the number of distinct haplotypes of phased samples in each
+/// window can be capped by removing the lowest-ranked records. They are applied
+/// after the cutoffs and before sorting.
+///
+/// The cutoff of each variant class (SNV, indel, and SV) can be set
+/// separately. Class-specific top fractions or counts refer to the ranked
+/// records of that class in the input, ordered by their global FORGe rank.
+///
+/// Linked records, e.g. in the same phase set, can be grouped to be kept or
+/// dropped together; each record in a group meets the cutoffs by the best
+/// rank of its group and the class of its best-ranked record, and is
+/// annotated with the group ID. Grouping buffers the input and cannot be
+/// combined with the density and haplotype caps.
+///
 /// # Arguments
 ///
 /// * `vcf_reader` - VCF input stream
 /// * `vcf_writer` - VCF output stream
 /// * `ranks_path` - FORGe ranking file path
-/// * `top` - This fraction of records will be written in the output stream
-/// * `annotate` - Whether annotate the records with FORGe ranking or not
-/// * `info_key` - VCF INFO key for FORGe ranking annotation
-pub fn filter<T, W, R>(
+/// * `opts` - Filter options; e.g. the top records to keep, annotations, and
+///   output order
+/// * `screen` - Screen of records by forced lists, FILTER column, and allele
+///   length caps; forced records are included in all outputs or excluded
+///   regardless of their rank, records failing the FILTER check are rejected,
+///   and oversized ones are dropped or passed through unranked with a FILTER
+///   tag
+/// * `rejected` - Optional VCF output stream for the records filtered out
+/// * `templated` - VCF output streams of each top value; `vcf_writer` is used
+///   for the only top value if it is empty
+pub fn filter<T, W, S>(
     mut vcf_writer: VCFWriter<BufWriter<W>>,
-    mut vcf_reader: VCFReader<BufReader<R>>,
+    mut vcf_reader: S,
     ranks_path: &T,
-    top: f64,
-    annotate: bool,
-    info_key: &String,
+    opts: &FilterOpt,
+    screen: &Screen,
+    rejected: Option<DynWriter>,
+    mut templated: Vec<DynWriter>,
 ) -> Result<(), VCFError>
 where
     T: AsRef<Path>,
     W: Write,
-    R: Read,
+    S: RecordRead,
 {
-    let ranks = forge::load_rank(ranks_path, top);
-    let mut vcf_record = VCFRecord::new(vcf_reader.header().clone());
-    loop {
-        let fetched = vcf_reader.next_record(&mut vcf_record)?;
-        if fetched {
-            match forge::forge_rank(&vcf_record, &ranks) {
-                Some(fr) => {
-                    if annotate {
-                        vcf_record.insert_info(
-                            info_key.as_bytes(),
-                            vec![format!("{}", fr).as_bytes().to_vec()],
-                        );
-                    }
-                    vcf_writer.write_record(&vcf_record)?;
+    let tmpdir = opts.tmpdir.clone().unwrap_or_else(std::env::temp_dir);
+    let class_tops = [opts.top_snv, opts.top_indel, opts.top_sv];
+    let by_class = class_tops.iter().any(|t| t.is_some());
+    let streaming = opts.top_basis == TopBasis::Rank && !by_class && opts.group_by.is_none();
+    let (ranking, mut cutoffs, mut buffered, groups) = if streaming {
+        let ranking = forge::load_rank(ranks_path, &opts.top);
+        let cutoffs: Vec<[usize; NOF_CLASSES]> = opts
+            .top
+            .iter()
+            .map(|t| [ranking.cutoff(t); NOF_CLASSES])
+            .collect();
+        (ranking, cutoffs, None, None)
+    } else {
+        let ranking = forge::load_rank(ranks_path, &[Top::Fraction(1.0)]);
+        let (ranks, groups, buffered) =
+            buffer_input(&mut vcf_reader, &ranking, opts, screen, &tmpdir)?;
+        let mut cutoffs: Vec<[usize; NOF_CLASSES]> = opts
+            .top
+            .iter()
+            .map(|t| match opts.top_basis {
+                TopBasis::Rank => [ranking.cutoff(t); NOF_CLASSES],
+                TopBasis::Vcf => [forge::cutoff(&ranks.all, ranks.all.len(), t); NOF_CLASSES],
+            })
+            .collect();
+        for (class, top) in zip(VariantClass::ALL, class_tops) {
+            if let Some(top) = top {
+                let order = &ranks.by_class[class.index()];
+                let cutoff = forge::cutoff(order, order.len(), &top);
+                for output_cutoffs in cutoffs.iter_mut() {
+                    output_cutoffs[class.index()] = cutoff;
                 }
-                None => {}
             }
-        } else {
-            break;
         }
+        (ranking, cutoffs, Some(buffered), groups)
+    };
+    let writers: Vec<&mut dyn RecordWrite> = if templated.is_empty() {
+        vec![&mut vcf_writer]
+    } else {
+        templated
+            .iter_mut()
+            .map(|w| w as &mut dyn RecordWrite)
+            .collect()
+    };
+    let mut outputs: Vec<Output> = zip(zip(&opts.top, cutoffs.drain(..)), writers)
+        .map(|((top, cutoffs), writer)| Output {
+            top: *top,
+            cutoffs,
+            writer,
+            sorter: match opts.order {
+                Order::Coord => None,
+                Order::Rank => Some(RankSorter::new(
+                    vcf_reader.header(),
+                    &tmpdir,
+                    opts.sort_buffer / opts.top.len(),
+                )),
+            },
+            stages: stages(opts),
+            counts: [0; NOF_CLASSES],
+        })
+        .collect();
+
+    let mut rejected = Rejected::new(rejected);
+    match buffered.as_mut() {
+        Some(reader) => write_records(
+            reader,
+            &mut outputs,
+            &ranking,
+            opts,
+            screen,
+            groups.as_ref(),
+            &mut rejected,
+        )?,
+        None => write_records(
+            &mut vcf_reader,
+            &mut outputs,
+            &ranking,
+            opts,
+            screen,
+            None,
+            &mut rejected,
+        )?,
+    }
+    for output in outputs {
+        output.finish(&mut rejected)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::density::WindowMode;
+    use crate::vcf_util::parse_vcf;
+
+    const VCF: &str = "##fileformat=VCFv4.2
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+x\t1\t.\tA\tC\t.\t.\t.
+x\t2\t.\tA\tC\t.\t.\t.
+x\t3\t.\tA\tC\t.\t.\t.
+";
+
+    /// Collector of the written records.
+    struct Positions(Vec<u64>);
+
+    impl RecordWrite for Positions {
+        fn write(&mut self, record: &VCFRecord) -> Result<(), VCFError> {
+            self.0.push(record.position);
+            Ok(())
+        }
+    }
+
+    /// Write the records ranked by their positions into an output with the
+    /// given stages, and get the written positions and the pending records.
+    fn run(stages: Vec<Box<dyn RecordStage>>) -> (Vec<u64>, usize) {
+        let (header, records) = parse_vcf(VCF);
+        let sink: Box<dyn Write> = Box::new(std::io::sink());
+        let writer = VCFWriter::new(BufWriter::new(sink), &header).unwrap();
+        let mut rejected = Rejected::new(Some(writer));
+        let mut positions = Positions(Vec::new());
+        let mut outputs = vec![Output {
+            top: Top::Fraction(1.0),
+            cutoffs: [usize::MAX; NOF_CLASSES],
+            writer: &mut positions,
+            sorter: None,
+            stages,
+            counts: [0; NOF_CLASSES],
+        }];
+        let mut max_pending = 0;
+        for (idx, record) in records.iter().enumerate() {
+            let rank = record.position as usize;
+            write_ranked(
+                &mut outputs,
+                idx,
+                rank,
+                VariantClass::Snv,
+                record,
+                &mut rejected,
+            )
+            .unwrap();
+            max_pending = std::cmp::max(max_pending, rejected.pending.len());
+        }
+        for output in outputs {
+            output.finish(&mut rejected).unwrap();
+        }
+        assert!(rejected.pending.is_empty());
+        (positions.0, max_pending)
+    }
+
+    #[test]
+    fn test_rejected_pending() {
+        // nothing waits for the decisions without stages
+        assert_eq!(run(Vec::new()), (vec![1, 2, 3], 0));
+        let cap = DensityCap::new(1, 10, WindowMode::Sliding);
+        let (positions, max_pending) = run(vec![Box::new(cap)]);
+        assert_eq!(positions, vec![1]);
+        assert_eq!(max_pending, 3);
+    }
+}
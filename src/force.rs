@@ -0,0 +1,180 @@
+use flate2::read::MultiGzDecoder;
+use log::warn;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use vcf::VCFRecord;
+
+use crate::vcf_util::is_gzipped_file;
+
+/// Alleles of a forced site; any allele at the position if not specified
+type Alleles = Option<(Vec<u8>, Vec<u8>)>;
+
+/// List of sites forced to be included or excluded.
+#[derive(Default)]
+pub struct ForceList {
+    sites: HashMap<Vec<u8>, HashMap<u64, Vec<Alleles>>>,
+}
+
+impl ForceList {
+    /// Load a list of sites from a VCF file or a TSV/CSV file of
+    /// `CHROM,POS[,REF,ALT]` lines, optionally gzipped.
+    ///
+    /// Lines containing a tab are split at tabs, and the others at commas.
+    /// Multi-allelic VCF records and TSV lines add one site per ALT allele.
+    pub fn load<T>(path: &T) -> Result<Self, std::io::Error>
+    where
+        T: AsRef<Path>,
+    {
+        let file = File::open(path)?;
+        let reader: Box<dyn Read> = if is_gzipped_file(path)? {
+            Box::new(MultiGzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let mut list = ForceList::default();
+        let mut is_vcf = false;
+        for (idx, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.starts_with('#') {
+                is_vcf |= line.starts_with("##fileformat=VCF") || line.starts_with("#CHROM");
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            // the ALT column of VCF and TSV lines may list several alleles
+            let fields: Vec<&str> = if is_vcf {
+                line.split('\t').collect()
+            } else if line.contains('\t') {
+                line.split('\t').map(str::trim).collect()
+            } else {
+                line.split(',').map(str::trim).collect()
+            };
+            // skip ID column of VCF records
+            let alleles = if is_vcf {
+                fields.get(3..5)
+            } else {
+                fields.get(2..4)
+            };
+            let pos = fields.get(1).and_then(|p| p.parse::<u64>().ok());
+            let (chrom, pos) = match (fields.first(), pos) {
+                (Some(chrom), Some(pos)) if !chrom.is_empty() => (chrom.as_bytes().to_vec(), pos),
+                _ => {
+                    warn!(
+                        "Skipping invalid site (line {}) in '{}'",
+                        idx + 1,
+                        path.as_ref().display()
+                    );
+                    continue;
+                }
+            };
+            let entry = list.sites.entry(chrom).or_default().entry(pos).or_default();
+            match alleles {
+                Some([reference, alts]) => {
+                    for alt in alts.split(',') {
+                        entry.push(Some((
+                            reference.as_bytes().to_vec(),
+                            alt.as_bytes().to_vec(),
+                        )));
+                    }
+                }
+                _ => entry.push(None),
+            }
+        }
+        Ok(list)
+    }
+
+    /// Number of positions in the list.
+    pub fn len(&self) -> usize {
+        self.sites.values().map(HashMap::len).sum()
+    }
+
+    /// Check whether the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
+    }
+
+    /// Check whether a record matches any site in the list.
+    ///
+    /// A site with alleles matches the records with the same REF allele and
+    /// any of their ALT alleles equal to its ALT allele.
+    pub fn contains(&self, record: &VCFRecord) -> bool {
+        match self
+            .sites
+            .get(&record.chromosome)
+            .and_then(|positions| positions.get(&record.position))
+        {
+            Some(sites) => sites.iter().any(|alleles| match alleles {
+                None => true,
+                Some((reference, alt)) => {
+                    *reference == record.reference && record.alternative.contains(alt)
+                }
+            }),
+            None => false,
+        }
+    }
+}
+
+/// Forced decision on a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forced {
+    Include,
+    Exclude,
+}
+
+/// Lists of sites forced to be included or excluded regardless of ranking.
+#[derive(Default)]
+pub struct ForceLists {
+    pub include: ForceList,
+    pub exclude: ForceList,
+}
+
+impl ForceLists {
+    /// Get the forced decision on a record, if any.
+    ///
+    /// Records in both lists are excluded.
+    pub fn check(&self, record: &VCFRecord) -> Option<Forced> {
+        if self.exclude.contains(record) {
+            Some(Forced::Exclude)
+        } else if self.include.contains(record) {
+            Some(Forced::Include)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, contents: &[u8]) -> ForceList {
+        let path =
+            std::env::temp_dir().join(format!("forgers-test-{}-{}.txt", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let list = ForceList::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        list.unwrap()
+    }
+
+    #[test]
+    fn test_load_short_files() {
+        assert!(load("empty", b"").is_empty());
+        assert!(load("newline", b"\n").is_empty());
+        assert!(load("one-byte", b"x").is_empty());
+    }
+
+    #[test]
+    fn test_load() {
+        let list = load("sites", b"x,10\nx\t20\tA\tC,G\nx, 30 ,T,G\ny,5\nbad\n");
+        assert_eq!(list.len(), 4);
+        let alleles = |chrom: &[u8], pos| list.sites[chrom][&pos].clone();
+        let site = |r: &[u8], a: &[u8]| Some((r.to_vec(), a.to_vec()));
+        assert_eq!(alleles(b"x", 10), vec![None]);
+        assert_eq!(alleles(b"x", 20), vec![site(b"A", b"C"), site(b"A", b"G")]);
+        assert_eq!(alleles(b"x", 30), vec![site(b"T", b"G")]);
+        assert_eq!(alleles(b"y", 5), vec![None]);
+    }
+}
@@ -8,6 +8,121 @@ use vcf::VCFRecord;
 pub type Region = Vec<u8>;
 pub type SiteMap = HashMap<u64, usize>;
 pub type RegSiteMap = HashMap<Region, SiteMap>;
+pub type ScoreMap = HashMap<u64, f64>;
+pub type RegScoreMap = HashMap<Region, ScoreMap>;
+
+/// FORGe ranking loaded from a rank file.
+pub struct Ranking {
+    /// FORGe rank of the loaded sites
+    pub sites: RegSiteMap,
+    /// FORGe score of the loaded sites, if provided by the rank file
+    pub scores: RegScoreMap,
+    /// Total number of entries in the rank file
+    pub total: usize,
+    /// FORGe rank of the loaded sites in the order of the rank file
+    pub order: Vec<usize>,
+}
+
+impl Ranking {
+    /// Get the highest FORGe rank within the given top records.
+    ///
+    /// A record is among the top records if its rank is less than or equal to
+    /// the returned cutoff.
+    pub fn cutoff(&self, top: &Top) -> usize {
+        cutoff(&self.order, self.total, top)
+    }
+}
+
+/// Get the highest FORGe rank within the given top records.
+///
+/// # Arguments
+///
+/// `order` - sorted FORGe ranks of the records
+/// `total` - total number of records which fractions refer to
+/// `top` - number of top records
+pub fn cutoff(order: &[usize], total: usize, top: &Top) -> usize {
+    match top.count(total) {
+        0 => 0,
+        n => order.get(n - 1).copied().unwrap_or(usize::MAX),
+    }
+}
+
+/// Number of top records specified either by a fraction or by a count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Top {
+    Fraction(f64),
+    Count(usize),
+}
+
+impl Top {
+    /// Number of top records out of `total` records.
+    pub fn count(&self, total: usize) -> usize {
+        match self {
+            Top::Fraction(fraction) => (fraction * total as f64) as usize,
+            Top::Count(count) => *count,
+        }
+    }
+}
+
+impl std::str::FromStr for Top {
+    type Err = String;
+
+    /// Parse a fraction in [0, 1] or a count greater than one.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(count) = s.parse::<usize>() {
+            if count > 1 {
+                return Ok(Top::Count(count));
+            }
+        }
+        match s.parse::<f64>() {
+            Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(Top::Fraction(fraction)),
+            _ => Err(format!(
+                "invalid top '{}': expected a fraction in [0, 1] or a count",
+                s
+            )),
+        }
+    }
+}
+
+/// Top fraction bins in ascending order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bins(pub Vec<f64>);
+
+impl std::str::FromStr for Bins {
+    type Err = String;
+
+    /// Parse comma-separated fractions in (0, 1] in strictly ascending order.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bins = Vec::new();
+        for token in s.split(',') {
+            match token.parse::<f64>() {
+                Ok(bin) if bin > 0.0 && bin <= 1.0 => bins.push(bin),
+                _ => {
+                    return Err(format!(
+                        "invalid bin '{}': expected a fraction in (0, 1]",
+                        token
+                    ))
+                }
+            }
+        }
+        if bins.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format!(
+                "invalid bins '{}': expected fractions in ascending order",
+                s
+            ));
+        }
+        Ok(Bins(bins))
+    }
+}
+
+impl std::fmt::Display for Top {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Top::Fraction(fraction) => write!(f, "{}", fraction),
+            Top::Count(count) => write!(f, "{}", count),
+        }
+    }
+}
 
 /// Prettify and truncate a token for logging
 ///
@@ -25,15 +140,22 @@ fn pretty_trunc(s: &str, n: usize) -> String {
 }
 
 /// Parse an entry in the FORGe ranking file
-pub fn parse_id(id: &str) -> Option<(Region, u64)> {
+///
+/// An entry is either `REGION,POS` or `REGION,POS,SCORE`.
+pub fn parse_id(id: &str) -> Option<(Region, u64, Option<f64>)> {
     let tokens: Vec<&str> = id.split(',').collect();
-    if tokens.len() != 2 {
-        return None;
-    }
+    let score = match tokens.len() {
+        2 => None,
+        3 => match tokens[2].parse::<f64>() {
+            Ok(score) => Some(score),
+            Err(_) => return None,
+        },
+        _ => return None,
+    };
     match (tokens[0], tokens[1].parse::<u64>()) {
         (region, Ok(pos)) => {
             if region.is_ascii() {
-                Some((region.as_bytes().to_vec(), pos))
+                Some((region.as_bytes().to_vec(), pos, score))
             } else {
                 warn!("Non-ASCII characters in the region name '{}'", region);
                 None
@@ -51,13 +173,36 @@ pub fn forge_rank<'a>(record: &VCFRecord, ranks: &'a RegSiteMap) -> Option<&'a u
     }
 }
 
-/// Load ranks file into a `RegSiteMap` instance
+/// Get FORGe score of a VCF record
+pub fn forge_score<'a>(record: &VCFRecord, scores: &'a RegScoreMap) -> Option<&'a f64> {
+    match scores.get(&record.chromosome) {
+        Some(scoremap) => scoremap.get(&record.position),
+        None => None,
+    }
+}
+
+/// Normalised FORGe rank in (0, 1]
+pub fn percentile(rank: usize, total: usize) -> f64 {
+    rank as f64 / total as f64
+}
+
+/// Find the smallest top fraction bin containing the given percentile
+///
+/// # Arguments
+///
+/// `percentile` - normalised FORGe rank
+/// `bins` - top fraction bins in ascending order
+pub fn fraction_bin(percentile: f64, bins: &[f64]) -> Option<f64> {
+    bins.iter().copied().find(|b| percentile <= *b)
+}
+
+/// Load ranks file into a `Ranking` instance
 ///
 /// # Arguments
 ///
 /// `path` - path to FORGe ranks file (output by FORGe's `rank.py`)
-/// `top` - only load first (100*`top`)% of variants in the file
-pub fn load_rank<T>(path: T, top: f64) -> RegSiteMap
+/// `tops` - only load the largest number of top variants in the file among these
+pub fn load_rank<T>(path: T, tops: &[Top]) -> Ranking
 where
     T: AsRef<std::path::Path>,
 {
@@ -70,17 +215,23 @@ where
         .expect("Cannot seek to start of file");
     let reader = BufReader::new(&file);
 
-    let n = (top * nof_records as f64) as usize;
+    let n = tops
+        .iter()
+        .map(|top| top.count(nof_records))
+        .max()
+        .unwrap_or(0);
+    let mut order = Vec::new();
     let mut smap = RegSiteMap::new();
+    let mut scores = RegScoreMap::new();
     let mut r: usize = 1;
     let mut i: usize = 0;
     for item in reader.split(b'\t') {
         match item {
             Ok(item) => {
                 let rec = String::from_utf8_lossy(&item);
-                match parse_id(&rec.trim_end()) {
-                    Some((region, pos)) => {
-                        let entry = smap.entry(region).or_insert(HashMap::new()).entry(pos);
+                match parse_id(rec.trim_end()) {
+                    Some((region, pos, score)) => {
+                        let entry = smap.entry(region.clone()).or_default().entry(pos);
 
                         match entry {
                             Entry::Occupied(_) => {
@@ -92,8 +243,12 @@ where
                             }
                             Entry::Vacant(v) => {
                                 v.insert(r);
+                                order.push(r);
+                                if let Some(score) = score {
+                                    scores.entry(region).or_default().insert(pos, score);
+                                }
                                 i += 1;
-                                if i == n {
+                                if i >= n {
                                     break;
                                 }
                             }
@@ -127,5 +282,32 @@ where
         warn!("Not enough distinct records in the rank file");
     }
 
-    smap
+    Ranking {
+        sites: smap,
+        scores,
+        total: nof_records,
+        order,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bins() {
+        assert_eq!("0.1,0.5,1".parse::<Bins>(), Ok(Bins(vec![0.1, 0.5, 1.0])));
+        assert!("0.5,0.1".parse::<Bins>().is_err());
+        assert!("0.1,0.1".parse::<Bins>().is_err());
+        assert!("0.1,1.5".parse::<Bins>().is_err());
+    }
+
+    #[test]
+    fn test_fraction_bin() {
+        let bins = [0.1, 0.5, 1.0];
+        assert_eq!(fraction_bin(0.05, &bins), Some(0.1));
+        assert_eq!(fraction_bin(0.1, &bins), Some(0.1));
+        assert_eq!(fraction_bin(0.7, &bins), Some(1.0));
+        assert_eq!(fraction_bin(0.7, &bins[..2]), None);
+    }
 }
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use vcf::VCFRecord;
+
+/// Criterion of linking records into groups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupBy {
+    /// Records sharing a phase set (FORMAT/PS) in any sample
+    PhaseSet,
+    /// Records sharing the value of an INFO field
+    Info(String),
+    /// Records chained by distances of at most the group window
+    Window,
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PS" => Ok(GroupBy::PhaseSet),
+            "window" => Ok(GroupBy::Window),
+            _ => match s.strip_prefix("INFO/") {
+                Some(key) if !key.is_empty() => Ok(GroupBy::Info(key.to_string())),
+                _ => Err(format!(
+                    "invalid grouping '{}': expected 'PS', 'INFO/<key>', or 'window'",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Groups of linked records with their best ranks.
+pub struct Grouping {
+    /// Best rank of the group of each record in the input order
+    pub ranks: Vec<Option<usize>>,
+    /// Index of the best-ranked record of the group of each record; the
+    /// first one on ties
+    pub best: Vec<Option<usize>>,
+    /// Group ID of each record in a group of more than one record
+    pub ids: Vec<Option<usize>>,
+    /// Number of groups of more than one record
+    pub nof_groups: usize,
+}
+
+/// Builder of groups of linked records.
+///
+/// Records are pushed in the input order and only linked on the same contig.
+pub struct Grouper {
+    by: GroupBy,
+    window: u64,
+    /// Union-find parent of each record
+    parent: Vec<usize>,
+    /// First record with each key on the current contig
+    keys: HashMap<Vec<u8>, usize>,
+    last: Option<(Vec<u8>, u64)>,
+}
+
+impl Grouper {
+    pub fn new(by: GroupBy, window: u64) -> Self {
+        Grouper {
+            by,
+            window,
+            parent: Vec::new(),
+            keys: HashMap::new(),
+            last: None,
+        }
+    }
+
+    fn find(&mut self, mut idx: usize) -> usize {
+        while self.parent[idx] != idx {
+            self.parent[idx] = self.parent[self.parent[idx]];
+            idx = self.parent[idx];
+        }
+        idx
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        // the root is the first record of the group
+        let (root, child) = (std::cmp::min(a, b), std::cmp::max(a, b));
+        self.parent[child] = root;
+    }
+
+    /// Get the keys linking a record to the others.
+    fn keys(&self, record: &VCFRecord) -> Vec<Vec<u8>> {
+        match &self.by {
+            GroupBy::PhaseSet => record
+                .header()
+                .samples()
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, sample)| {
+                    let ps = record.genotype(sample, b"PS")?.first()?;
+                    if ps.is_empty() || ps == b"." {
+                        return None;
+                    }
+                    let mut key = idx.to_le_bytes().to_vec();
+                    key.extend(ps);
+                    Some(key)
+                })
+                .collect(),
+            GroupBy::Info(key) => match record.info(key.as_bytes()) {
+                Some(values) if !values.is_empty() && values != &[b".".to_vec()] => {
+                    vec![values.join(&b","[..])]
+                }
+                _ => Vec::new(),
+            },
+            GroupBy::Window => Vec::new(),
+        }
+    }
+
+    /// Add the next record.
+    pub fn push(&mut self, record: &VCFRecord) {
+        let idx = self.parent.len();
+        self.parent.push(idx);
+        let same_contig = self
+            .last
+            .as_ref()
+            .is_some_and(|(chrom, _)| *chrom == record.chromosome);
+        if !same_contig {
+            self.keys.clear();
+        }
+        if self.by == GroupBy::Window {
+            if let Some((_, pos)) = self.last.as_ref().filter(|_| same_contig) {
+                if record.position.saturating_sub(*pos) <= self.window {
+                    self.union(idx - 1, idx);
+                }
+            }
+        }
+        for key in self.keys(record) {
+            match self.keys.get(&key) {
+                Some(first) => self.union(*first, idx),
+                None => {
+                    self.keys.insert(key, idx);
+                }
+            }
+        }
+        self.last = Some((record.chromosome.clone(), record.position));
+    }
+
+    /// Compute the best rank of each group.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranks` - Rank of each record in the input order; `None` for unranked
+    ///   records and those not competing for the top cutoffs
+    pub fn finish(mut self, ranks: &[Option<usize>]) -> Grouping {
+        let n = self.parent.len();
+        let roots: Vec<usize> = (0..n).map(|i| self.find(i)).collect();
+        let mut best: Vec<Option<(usize, usize)>> = vec![None; n];
+        let mut sizes = vec![0; n];
+        for (idx, root) in roots.iter().enumerate() {
+            sizes[*root] += 1;
+            if let Some(rank) = ranks[idx] {
+                best[*root] =
+                    Some(best[*root].map_or((rank, idx), |b| std::cmp::min(b, (rank, idx))));
+            }
+        }
+        let mut group_ids: HashMap<usize, usize> = HashMap::new();
+        let mut ids = Vec::with_capacity(n);
+        for root in roots.iter() {
+            if sizes[*root] > 1 {
+                let next = group_ids.len() + 1;
+                ids.push(Some(*group_ids.entry(*root).or_insert(next)));
+            } else {
+                ids.push(None);
+            }
+        }
+        Grouping {
+            ranks: roots.iter().map(|root| best[*root].map(|b| b.0)).collect(),
+            best: roots.iter().map(|root| best[*root].map(|b| b.1)).collect(),
+            ids,
+            nof_groups: group_ids.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::parse_vcf;
+
+    /// Group records of `CHROM POS INFO PS1 PS2` lines, and get the group ID
+    /// of each record.
+    fn group(by: GroupBy, window: u64, sites: &[&str]) -> Vec<Option<usize>> {
+        let ranks = vec![None; sites.len()];
+        groups(by, window, sites, &ranks).ids
+    }
+
+    fn groups(by: GroupBy, window: u64, sites: &[&str], ranks: &[Option<usize>]) -> Grouping {
+        let mut text = "##fileformat=VCFv4.2
+##INFO=<ID=EV,Number=1,Type=String,Description=\"Event\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+##FORMAT=<ID=PS,Number=1,Type=Integer,Description=\"Phase set\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2
+"
+        .to_string();
+        for site in sites {
+            let f: Vec<&str> = site.split(' ').collect();
+            text.push_str(&format!(
+                "{}\t{}\t.\tA\tC\t.\t.\t{}\tGT:PS\t0|1:{}\t0|1:{}\n",
+                f[0], f[1], f[2], f[3], f[4]
+            ));
+        }
+        let (_, records) = parse_vcf(&text);
+        let mut grouper = Grouper::new(by, window);
+        for record in records.iter() {
+            grouper.push(record);
+        }
+        grouper.finish(ranks)
+    }
+
+    #[test]
+    fn test_group_by_phase_set() {
+        let sites = [
+            "x 1 . 100 .",
+            "x 2 . . 100",
+            "x 3 . 100 200",
+            "x 4 . . 200",
+            "x 5 . . .",
+            "y 6 . 100 .",
+        ];
+        // phase sets are only shared within a sample and a contig
+        assert_eq!(
+            group(GroupBy::PhaseSet, 0, &sites),
+            vec![Some(1), None, Some(1), Some(1), None, None]
+        );
+    }
+
+    #[test]
+    fn test_group_by_info() {
+        let sites = [
+            "x 1 EV=a . .",
+            "x 2 EV=b . .",
+            "x 3 EV=a . .",
+            "x 4 . . .",
+            "x 5 EV=. . .",
+            "x 6 EV=b . .",
+            "y 7 EV=a . .",
+            "y 8 EV=a . .",
+        ];
+        let by = GroupBy::Info("EV".to_string());
+        assert_eq!(
+            group(by, 0, &sites),
+            vec![
+                Some(1),
+                Some(2),
+                Some(1),
+                None,
+                None,
+                Some(2),
+                Some(3),
+                Some(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_by_window() {
+        let sites = [
+            "x 1 . . .",
+            "x 4 . . .",
+            "x 9 . . .",
+            "x 20 . . .",
+            "y 21 . . .",
+            "y 24 . . .",
+        ];
+        // chained across the window, but not across contigs
+        assert_eq!(
+            group(GroupBy::Window, 5, &sites),
+            vec![Some(1), Some(1), Some(1), None, Some(2), Some(2)]
+        );
+    }
+
+    #[test]
+    fn test_group_ranks() {
+        let sites = [
+            "x 1 EV=a . .",
+            "x 2 EV=a . .",
+            "x 3 EV=a . .",
+            "x 4 EV=b . .",
+            "x 5 EV=b . .",
+            "x 6 . . .",
+        ];
+        let ranks = [None, Some(5), Some(3), None, None, Some(7)];
+        let grouping = groups(GroupBy::Info("EV".to_string()), 0, &sites, &ranks);
+        // the best rank is propagated to the unranked members
+        assert_eq!(
+            grouping.ranks,
+            vec![Some(3), Some(3), Some(3), None, None, Some(7)]
+        );
+        assert_eq!(
+            grouping.best,
+            vec![Some(2), Some(2), Some(2), None, None, Some(5)]
+        );
+        assert_eq!(grouping.nof_groups, 2);
+
+        // ties are broken by the input order
+        let ranks = [Some(3), Some(5), Some(3), Some(1), Some(1), None];
+        let grouping = groups(GroupBy::Info("EV".to_string()), 0, &sites, &ranks);
+        assert_eq!(
+            grouping.best,
+            vec![Some(0), Some(0), Some(0), Some(3), Some(3), None]
+        );
+    }
+}
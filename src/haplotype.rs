@@ -0,0 +1,198 @@
+use log::{info, warn};
+use std::collections::{HashSet, VecDeque};
+use vcf::VCFRecord;
+
+use crate::vcf_util::{parse_genotype, Emitted, Genotype, RecordStage, Staged};
+
+/// A buffered record waiting for the decision of the haplotype cap.
+struct Entry {
+    staged: Staged,
+    /// Alleles of each haplotype of each sample; `None` if unknown, e.g.
+    /// unphased heterozygous or missing genotypes
+    alleles: Vec<Vec<Option<usize>>>,
+    removed: bool,
+}
+
+/// Get the alleles of each haplotype of each sample in a record.
+fn haplotype_alleles(record: &VCFRecord) -> Vec<Vec<Option<usize>>> {
+    record
+        .header()
+        .samples()
+        .iter()
+        .map(
+            |sample| match parse_genotype(record.genotype(sample, b"GT")) {
+                Ok(Genotype::Phased(v)) => v.into_iter().map(Some).collect(),
+                // an unphased genotype is only known if homozygous
+                Ok(Genotype::Unphased(v)) if v.iter().all(|a| *a == v[0]) => {
+                    v.into_iter().map(Some).collect()
+                }
+                Ok(Genotype::Unphased(v)) => vec![None; v.len()],
+                _ => Vec::new(),
+            },
+        )
+        .collect()
+}
+
+/// Cap on the number of distinct haplotypes in every genomic window.
+///
+/// The haplotypes are the allele sequences of the phased sample haplotypes
+/// over the kept records in a window. Haplotypes with any unknown allele in a
+/// window are not counted; i.e. unphased heterozygous and missing genotypes.
+/// Samples may have any ploidy; the haplotypes missing in records of a lower
+/// ploidy, e.g. haploid calls beyond a PAR boundary, are unknown as well.
+///
+/// Windows are processed in coordinate order, each starting at a record; the
+/// lowest-ranked records of a window are removed until its number of distinct
+/// haplotypes is at most `max`. Removing records never increases the number of
+/// haplotypes in the windows already processed. Exempted records count but are
+/// never removed.
+///
+/// Records must be pushed in coordinate order. Only the records in the last
+/// window length on the current contig are buffered.
+pub struct HaplotypeCap {
+    max: usize,
+    window: u64,
+    buffer: VecDeque<Entry>,
+    removed: usize,
+    windows: usize,
+}
+
+impl HaplotypeCap {
+    pub fn new(max: usize, window: u64) -> Self {
+        HaplotypeCap {
+            max,
+            window: std::cmp::max(window, 1),
+            buffer: VecDeque::new(),
+            removed: 0,
+            windows: 0,
+        }
+    }
+
+    /// Count distinct haplotypes over the kept records in the buffer at the
+    /// given indices.
+    fn count(&self, members: &[usize]) -> usize {
+        let kept: Vec<&Entry> = members
+            .iter()
+            .map(|i| &self.buffer[*i])
+            .filter(|e| !e.removed)
+            .collect();
+        let nof_samples = kept.first().map_or(0, |e| e.alleles.len());
+        let mut haplotypes = HashSet::new();
+        for sample in 0..nof_samples {
+            let ploidy = kept
+                .iter()
+                .map(|e| e.alleles[sample].len())
+                .max()
+                .unwrap_or(0);
+            for hap in 0..ploidy {
+                let haplotype: Option<Vec<usize>> = kept
+                    .iter()
+                    .map(|e| e.alleles[sample].get(hap).copied().flatten())
+                    .collect();
+                if let Some(haplotype) = haplotype {
+                    haplotypes.insert(haplotype);
+                }
+            }
+        }
+        haplotypes.len()
+    }
+
+    /// Remove the lowest-ranked records of the window starting at the front
+    /// record until it has no more than `max` distinct haplotypes.
+    fn cap_front(&mut self) {
+        let front = &self.buffer[0].staged.record;
+        let (start, end) = (front.position, front.position + self.window - 1);
+        let chrom = front.chromosome.clone();
+        let members: Vec<usize> = (0..self.buffer.len())
+            .take_while(|i| self.buffer[*i].staged.record.position <= end)
+            .collect();
+        let before = self.count(&members);
+        let mut count = before;
+        let mut removed = 0;
+        while count > self.max {
+            let lowest = members
+                .iter()
+                .filter(|i| !self.buffer[**i].removed && self.buffer[**i].staged.class.is_some())
+                .max_by_key(|i| (self.buffer[**i].staged.rank, **i));
+            match lowest {
+                Some(idx) => {
+                    self.buffer[*idx].removed = true;
+                    removed += 1;
+                    count = self.count(&members);
+                }
+                None => {
+                    warn!(
+                        "Cannot reduce {} haplotypes in window {}:{}-{} removing no exempted records",
+                        count,
+                        String::from_utf8_lossy(&chrom),
+                        start,
+                        end
+                    );
+                    break;
+                }
+            }
+        }
+        if removed > 0 {
+            self.windows += 1;
+            self.removed += removed;
+            info!(
+                "Removed {} records in window {}:{}-{} reducing distinct haplotypes from {} to {}",
+                removed,
+                String::from_utf8_lossy(&chrom),
+                start,
+                end,
+                before,
+                count
+            );
+        }
+    }
+
+    /// Process the window of the front record and emit it if kept.
+    fn pop_front(&mut self, emitted: &mut Emitted) {
+        self.cap_front();
+        let entry = self.buffer.pop_front().unwrap();
+        if entry.removed {
+            emitted.removed.push(entry.staged);
+        } else {
+            emitted.kept.push(entry.staged);
+        }
+    }
+}
+
+impl RecordStage for HaplotypeCap {
+    fn push(&mut self, staged: Staged, emitted: &mut Emitted) {
+        let same_contig = self
+            .buffer
+            .back()
+            .is_none_or(|e| e.staged.record.chromosome == staged.record.chromosome);
+        if !same_contig {
+            self.flush(emitted);
+        }
+        // the window of the front record is complete
+        while self
+            .buffer
+            .front()
+            .is_some_and(|e| staged.record.position >= e.staged.record.position + self.window)
+        {
+            self.pop_front(emitted);
+        }
+        self.buffer.push_back(Entry {
+            alleles: haplotype_alleles(&staged.record),
+            staged,
+            removed: false,
+        });
+    }
+
+    fn flush(&mut self, emitted: &mut Emitted) {
+        while !self.buffer.is_empty() {
+            self.pop_front(emitted);
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "Removed {} records in {} windows exceeding {} distinct haplotypes per {} bp",
+            self.removed, self.windows, self.max, self.window
+        )
+    }
+}
@@ -0,0 +1,801 @@
+use flate2::read::MultiGzDecoder;
+use log::{info, warn};
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{sink, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use vcf::{VCFError, VCFHeader, VCFRecord};
+
+use crate::contig::ContigSelector;
+use crate::region::{merge_regions, record_end, GenomicRegion, RegionSelector};
+use crate::vcf_util::RecordRead;
+
+/// Minimum shift and depth of the binning scheme of tabix indices
+const TBI_MIN_SHIFT: u32 = 14;
+const TBI_DEPTH: u32 = 5;
+
+/// A chunk of a BGZF file between two virtual offsets.
+struct Chunk {
+    begin: u64,
+    end: u64,
+}
+
+/// Bins of a reference sequence in the index.
+struct RefIndex {
+    bins: Vec<(u32, Vec<Chunk>)>,
+    /// Linear index of tabix indices; empty for CSI indices
+    intervals: Vec<u64>,
+}
+
+/// Tabix or CSI index of a BGZF-compressed VCF file.
+pub struct Index {
+    min_shift: u32,
+    depth: u32,
+    names: Vec<Vec<u8>>,
+    refs: Vec<RefIndex>,
+}
+
+/// Little-endian cursor over the decompressed index.
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], std::io::Error> {
+        if self.pos + n > self.buf.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "truncated index file",
+            ));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, std::io::Error> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, std::io::Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, std::io::Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, std::io::Error> {
+        usize::try_from(self.i32()?)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "negative length"))
+    }
+}
+
+/// Parse sequence names stored in tabix meta data as NUL-terminated strings.
+fn parse_names(cursor: &mut Cursor) -> Result<Vec<Vec<u8>>, std::io::Error> {
+    // format, col_seq, col_beg, col_end, meta, skip
+    cursor.take(6 * 4)?;
+    let l_nm = cursor.len()?;
+    let names = cursor.take(l_nm)?;
+    Ok(names
+        .split(|c| *c == 0)
+        .filter(|n| !n.is_empty())
+        .map(|n| n.to_vec())
+        .collect())
+}
+
+/// Check whether a file starts with a BGZF block; i.e. a gzip member with the
+/// `BC` extra subfield.
+fn is_bgzf<T>(path: &T) -> Result<bool, std::io::Error>
+where
+    T: AsRef<Path>,
+{
+    let mut magic = [0u8; 16];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic[..4] == [0x1f, 0x8b, 0x08, 0x04] && magic[12..14] == *b"BC"),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compute the bins overlapping the 0-based half-open interval [beg, end).
+fn reg2bins(beg: u64, end: u64, min_shift: u32, depth: u32) -> HashSet<u32> {
+    let max_pos = 1u64 << (min_shift + depth * 3);
+    let end = std::cmp::min(end, max_pos) - 1;
+    let beg = std::cmp::min(beg, end);
+    let mut bins = HashSet::new();
+    let mut shift = min_shift + depth * 3;
+    let mut first = 0u64;
+    for level in 0..=depth {
+        let b = first + (beg >> shift);
+        let e = first + (end >> shift);
+        bins.extend((b..=e).map(|x| x as u32));
+        shift -= 3;
+        first += 1 << (level * 3);
+    }
+    bins
+}
+
+impl Index {
+    /// Find the index file of a VCF file; i.e. `<path>.tbi` or `<path>.csi`.
+    pub fn find<T>(path: &T) -> Option<PathBuf>
+    where
+        T: AsRef<Path>,
+    {
+        ["tbi", "csi"]
+            .iter()
+            .map(|ext| PathBuf::from(format!("{}.{}", path.as_ref().display(), ext)))
+            .find(|p| p.is_file())
+    }
+
+    /// Load a tabix or CSI index.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - path to the index file
+    /// `header` - VCF header; its contig order is used if the index has no
+    ///   sequence names
+    pub fn load<T>(path: &T, header: &VCFHeader) -> Result<Self, std::io::Error>
+    where
+        T: AsRef<Path>,
+    {
+        let mut buf = Vec::new();
+        MultiGzDecoder::new(File::open(path)?).read_to_end(&mut buf)?;
+        let mut cursor = Cursor { buf: &buf, pos: 0 };
+        let magic = cursor.take(4)?;
+        let is_csi = match magic {
+            b"TBI\x01" => false,
+            b"CSI\x01" => true,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unknown index format",
+                ))
+            }
+        };
+        let (min_shift, depth, n_ref, names) = if is_csi {
+            let min_shift = cursor.u32()?;
+            let depth = cursor.u32()?;
+            let l_aux = cursor.len()?;
+            let aux = cursor.take(l_aux)?;
+            let names = if l_aux >= 7 * 4 {
+                parse_names(&mut Cursor { buf: aux, pos: 0 })?
+            } else {
+                crate::region::header_contigs(header)
+            };
+            (min_shift, depth, cursor.len()?, names)
+        } else {
+            let n_ref = cursor.len()?;
+            let names = parse_names(&mut cursor)?;
+            (TBI_MIN_SHIFT, TBI_DEPTH, n_ref, names)
+        };
+
+        let mut refs = Vec::with_capacity(n_ref);
+        for _ in 0..n_ref {
+            let n_bin = cursor.len()?;
+            let mut bins = Vec::with_capacity(n_bin);
+            for _ in 0..n_bin {
+                let bin = cursor.u32()?;
+                if is_csi {
+                    cursor.u64()?; // loffset
+                }
+                let n_chunk = cursor.len()?;
+                let mut chunks = Vec::with_capacity(n_chunk);
+                for _ in 0..n_chunk {
+                    let begin = cursor.u64()?;
+                    let end = cursor.u64()?;
+                    chunks.push(Chunk { begin, end });
+                }
+                bins.push((bin, chunks));
+            }
+            let mut intervals = Vec::new();
+            if !is_csi {
+                let n_intv = cursor.len()?;
+                for _ in 0..n_intv {
+                    intervals.push(cursor.u64()?);
+                }
+            }
+            refs.push(RefIndex { bins, intervals });
+        }
+        Ok(Index {
+            min_shift,
+            depth,
+            names,
+            refs,
+        })
+    }
+
+    /// Sequence names in the order of the indexed file.
+    pub fn names(&self) -> &[Vec<u8>] {
+        &self.names
+    }
+
+    /// Get the smallest virtual offset of the records overlapping a region.
+    ///
+    /// It returns `None` if no record can overlap the region.
+    pub fn query(&self, region: &GenomicRegion) -> Option<u64> {
+        self.query_from(region, self.window(region.start))
+    }
+
+    /// Window of the linear index containing a position.
+    fn window(&self, pos: u64) -> u64 {
+        pos.saturating_sub(1) >> self.min_shift
+    }
+
+    /// Get the smallest virtual offset of the records overlapping the range
+    /// from the start of a window of the linear index to the end of a region.
+    ///
+    /// The records before the offset end before the window.
+    fn query_from(&self, region: &GenomicRegion, window: u64) -> Option<u64> {
+        let tid = self.names.iter().position(|n| *n == region.chrom)?;
+        let ref_index = self.refs.get(tid)?;
+        let beg = std::cmp::min(region.start - 1, window << self.min_shift);
+        let bins = reg2bins(beg, region.end, self.min_shift, self.depth);
+        // the linear index of tabix indices covers the ends of all records
+        let min_offset = match ref_index.intervals.is_empty() {
+            true => 0,
+            false => *ref_index.intervals.get((beg >> TBI_MIN_SHIFT) as usize)?,
+        };
+        ref_index
+            .bins
+            .iter()
+            .filter(|(bin, _)| bins.contains(bin))
+            .flat_map(|(_, chunks)| chunks.iter())
+            .filter(|chunk| chunk.end > min_offset)
+            .map(|chunk| std::cmp::max(chunk.begin, min_offset))
+            .min()
+    }
+}
+
+/// Record source reading the records overlapping a set of regions by random
+/// access to a BGZF-compressed VCF file using its index.
+pub struct IndexedReader {
+    path: PathBuf,
+    header: VCFHeader,
+    index: Index,
+    regions: VecDeque<GenomicRegion>,
+    region: Option<GenomicRegion>,
+    stream: Option<BufReader<MultiGzDecoder<BufReader<File>>>>,
+    /// Whether the regions are extended to whole clusters of overlapping
+    /// records
+    extend: bool,
+    /// Whether a record on the contig of the current region has been read
+    on_contig: bool,
+    selector: RegionSelector,
+    selected: VecDeque<VCFRecord>,
+    record: VCFRecord,
+    line: Vec<u8>,
+    line_num: u64,
+    /// Position and lines of the last records yielded, to skip them when
+    /// they are read again for the next region
+    last: Option<(Vec<u8>, u64)>,
+    last_lines: HashSet<Vec<u8>>,
+}
+
+impl IndexedReader {
+    /// Open an indexed reader if the VCF file has an index.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - path to the BGZF-compressed VCF file
+    /// `header` - VCF header
+    /// `regions` - regions to be read
+    /// `extend` - extend the regions to whole clusters of overlapping records
+    pub fn open<T>(
+        path: &T,
+        header: &VCFHeader,
+        regions: Vec<GenomicRegion>,
+        extend: bool,
+    ) -> Result<Option<Self>, std::io::Error>
+    where
+        T: AsRef<Path>,
+    {
+        Self::open_with(path, header, extend, |_| regions)
+    }
+
+    /// Open an indexed reader of the whole selected contigs if the VCF file
+    /// has an index.
+    ///
+    /// The records on the other contigs are never decoded.
+    ///
+    /// # Arguments
+    ///
+    /// `path` - path to the BGZF-compressed VCF file
+    /// `header` - VCF header
+    /// `selector` - selector of the contigs to be read
+    pub fn open_contigs<T>(
+        path: &T,
+        header: &VCFHeader,
+        selector: &ContigSelector,
+    ) -> Result<Option<Self>, std::io::Error>
+    where
+        T: AsRef<Path>,
+    {
+        Self::open_with(path, header, false, |index| {
+            index
+                .names()
+                .iter()
+                .filter(|name| selector.is_selected(name))
+                .map(|name| GenomicRegion {
+                    chrom: name.clone(),
+                    start: 1,
+                    end: u64::MAX,
+                })
+                .collect()
+        })
+    }
+
+    /// Open an indexed reader of the regions given by the index, if any.
+    fn open_with<T, F>(
+        path: &T,
+        header: &VCFHeader,
+        extend: bool,
+        regions: F,
+    ) -> Result<Option<Self>, std::io::Error>
+    where
+        T: AsRef<Path>,
+        F: FnOnce(&Index) -> Vec<GenomicRegion>,
+    {
+        let index_path = match Index::find(path) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        if !is_bgzf(path)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "indexed file not BGZF-compressed",
+            ));
+        }
+        info!("Using index file '{}'", index_path.display());
+        let index = Index::load(&index_path, header)?;
+        let regions = merge_regions(regions(&index), index.names());
+        Ok(Some(IndexedReader {
+            path: path.as_ref().to_path_buf(),
+            header: header.clone(),
+            selector: RegionSelector::new(&regions, extend),
+            extend,
+            index,
+            regions: regions.into(),
+            region: None,
+            stream: None,
+            on_contig: false,
+            selected: VecDeque::new(),
+            record: VCFRecord::new(header.clone()),
+            line: Vec::new(),
+            line_num: 0,
+            last: None,
+            last_lines: HashSet::new(),
+        }))
+    }
+
+    /// Open a stream at a virtual offset.
+    fn open_at(
+        &self,
+        offset: u64,
+    ) -> Result<BufReader<MultiGzDecoder<BufReader<File>>>, std::io::Error> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset >> 16))?;
+        let mut stream = BufReader::new(MultiGzDecoder::new(BufReader::new(file)));
+        std::io::copy(&mut (&mut stream).take(offset & 0xffff), &mut sink())?;
+        Ok(stream)
+    }
+
+    /// Get the virtual offset to read a region from, including the whole
+    /// chain of overlapping records leading into it if the regions are
+    /// extended.
+    ///
+    /// The linear index skips the records ending before the window of the
+    /// region start, which may belong to the chain; so earlier windows are
+    /// read until the chain starts after the skipped records.
+    fn region_offset(&self, region: &GenomicRegion) -> Result<Option<u64>, VCFError> {
+        let mut window = self.index.window(region.start);
+        let mut offset = match self.index.query_from(region, window) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        while self.extend && window > 0 && !self.is_chain_complete(region, offset, window)? {
+            window -= 1;
+            offset = self.index.query_from(region, window).unwrap_or(offset);
+        }
+        Ok(Some(offset))
+    }
+
+    /// Check whether the chain of overlapping records leading into a region
+    /// starts after the records skipped by reading from a virtual offset,
+    /// which all end before the given window of the linear index.
+    fn is_chain_complete(
+        &self,
+        region: &GenomicRegion,
+        offset: u64,
+        window: u64,
+    ) -> Result<bool, VCFError> {
+        let window_start = window << self.index.min_shift;
+        let mut stream = self.open_at(offset)?;
+        let mut record = VCFRecord::new(self.header.clone());
+        let mut line = Vec::new();
+        let mut chain: Option<(u64, u64)> = None;
+        loop {
+            line.clear();
+            if stream.read_until(b'\n', &mut line)? == 0 {
+                return Ok(true);
+            }
+            if line.starts_with(b"#") {
+                continue;
+            }
+            record.parse_bytes(&line, 0)?;
+            if record.chromosome != region.chrom {
+                match chain {
+                    Some(_) => return Ok(true),
+                    None => continue,
+                }
+            }
+            let (start, end) = (record.position, record_end(&record));
+            // start and end of the chain of overlapping records read so far
+            let (first, chain_end) = match chain {
+                Some((_, chain_end)) if start > chain_end => return Ok(true),
+                Some((first, chain_end)) => (first, std::cmp::max(chain_end, end)),
+                None => (start, end),
+            };
+            if start > region.end {
+                return Ok(true);
+            }
+            if end >= region.start {
+                // a skipped record may overlap the first one read
+                return Ok(first > window_start);
+            }
+            chain = Some((first, chain_end));
+        }
+    }
+
+    /// Seek to the first record of the next region with any record.
+    fn next_region(&mut self) -> Result<bool, VCFError> {
+        self.stream = None;
+        while let Some(region) = self.regions.pop_front() {
+            if let Some(offset) = self.region_offset(&region)? {
+                self.stream = Some(self.open_at(offset)?);
+                self.region = Some(region);
+                self.on_contig = false;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Check whether the record was already yielded for a previous region.
+    fn is_yielded(&self) -> bool {
+        match &self.last {
+            Some((chrom, pos)) if *chrom == self.record.chromosome => {
+                self.record.position < *pos
+                    || (self.record.position == *pos && self.last_lines.contains(&self.line))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl RecordRead for IndexedReader {
+    fn header(&self) -> &VCFHeader {
+        &self.header
+    }
+
+    fn next_record(&mut self, record: &mut VCFRecord) -> Result<bool, VCFError> {
+        while self.selected.is_empty() {
+            if self.stream.is_none() && !self.next_region()? {
+                return Ok(false);
+            }
+            let stream = self.stream.as_mut().unwrap();
+            self.line.clear();
+            if stream.read_until(b'\n', &mut self.line)? == 0 {
+                self.stream = None;
+                continue;
+            }
+            self.line_num += 1;
+            if self.line.starts_with(b"#") {
+                continue;
+            }
+            self.record.parse_bytes(&self.line, self.line_num)?;
+            let region = self.region.as_ref().unwrap();
+            if self.record.chromosome != region.chrom {
+                if self.on_contig {
+                    self.stream = None;
+                }
+                continue;
+            }
+            self.on_contig = true;
+            if self
+                .selector
+                .is_passed(&self.record.chromosome, self.record.position, region)
+            {
+                self.stream = None;
+                continue;
+            }
+            if self.is_yielded() {
+                continue;
+            }
+            self.selector.feed(&self.record, &mut self.selected);
+            if !self.selected.is_empty() {
+                let pos = self.record.position;
+                if self.last.as_ref().is_none_or(|(_, p)| *p != pos) {
+                    self.last_lines.clear();
+                }
+                self.last_lines.insert(self.line.clone());
+                self.last = Some((self.record.chromosome.clone(), pos));
+            }
+        }
+        *record = self.selected.pop_front().unwrap();
+        Ok(true)
+    }
+}
+
+/// Warn about regions or contigs given for non-indexed input.
+pub fn warn_no_index<T>(path: &T)
+where
+    T: AsRef<Path>,
+{
+    warn!(
+        "No index found for '{}'; filtering regions/contigs by streaming the whole input",
+        path.as_ref().display()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::RegionFilter;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::{Compression, Crc};
+    use std::io::Write;
+    use vcf::VCFReader;
+
+    const VCF: &str = "test/data/x.vcf.gz";
+
+    fn header() -> VCFHeader {
+        let file = File::open(VCF).unwrap();
+        let reader = VCFReader::new(BufReader::new(MultiGzDecoder::new(file))).unwrap();
+        reader.header().clone()
+    }
+
+    fn region(chrom: &str, start: u64, end: u64) -> GenomicRegion {
+        GenomicRegion {
+            chrom: chrom.as_bytes().to_vec(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_reg2bins() {
+        let bins = |beg, end| {
+            let mut bins: Vec<u32> = reg2bins(beg, end, TBI_MIN_SHIFT, TBI_DEPTH)
+                .into_iter()
+                .collect();
+            bins.sort();
+            bins
+        };
+        assert_eq!(bins(0, 1), vec![0, 1, 9, 73, 585, 4681]);
+        assert_eq!(bins(0, 1 << 14), vec![0, 1, 9, 73, 585, 4681]);
+        assert_eq!(bins(0, (1 << 14) + 1), vec![0, 1, 9, 73, 585, 4681, 4682]);
+        assert_eq!(bins(1 << 17, (1 << 17) + 1), vec![0, 1, 9, 73, 586, 4689]);
+        // clipped to the maximum position of the binning scheme
+        assert_eq!(
+            bins(u64::MAX - 1, u64::MAX),
+            vec![0, 8, 72, 584, 4680, 37448]
+        );
+    }
+
+    #[test]
+    fn test_load_tabix() {
+        let index = Index::load(&format!("{}.tbi", VCF), &header()).unwrap();
+        assert_eq!(index.names(), &[b"x".to_vec()]);
+        assert_eq!((index.min_shift, index.depth), (14, 5));
+        assert_eq!(index.refs.len(), 1);
+        assert_eq!(index.refs[0].bins.len(), 1);
+        assert_eq!(index.refs[0].bins[0].0, 4681);
+        assert_eq!(index.refs[0].intervals.len(), 1);
+
+        let first = index.refs[0].intervals[0];
+        assert_eq!(index.query(&region("x", 1, 100)), Some(first));
+        assert_eq!(index.query(&region("x", 900, u64::MAX)), Some(first));
+        // beyond the linear index
+        assert_eq!(index.query(&region("x", 20000, 30000)), None);
+        assert_eq!(index.query(&region("y", 1, 100)), None);
+    }
+
+    #[test]
+    fn test_load_csi() {
+        let mut data = b"CSI\x01".to_vec();
+        let mut push = |v: &[u8]| data.extend_from_slice(v);
+        push(&14u32.to_le_bytes()); // min_shift
+        push(&5u32.to_le_bytes()); // depth
+        push(&30i32.to_le_bytes()); // l_aux
+        for v in [2i32, 1, 2, 0, 35, 0] {
+            push(&v.to_le_bytes());
+        }
+        push(&2i32.to_le_bytes()); // l_nm
+        push(b"x\0");
+        push(&1i32.to_le_bytes()); // n_ref
+        push(&1i32.to_le_bytes()); // n_bin
+        push(&4681u32.to_le_bytes());
+        push(&0x100u64.to_le_bytes()); // loffset
+        push(&1i32.to_le_bytes()); // n_chunk
+        push(&0x100u64.to_le_bytes());
+        push(&0x200u64.to_le_bytes());
+
+        let path = std::env::temp_dir().join(format!("forgers-test-{}.csi", std::process::id()));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap();
+        let index = Index::load(&path, &header());
+        std::fs::remove_file(&path).unwrap();
+        let index = index.unwrap();
+
+        assert_eq!(index.names(), &[b"x".to_vec()]);
+        assert!(index.refs[0].intervals.is_empty());
+        assert_eq!(index.query(&region("x", 1, 100)), Some(0x100));
+        assert_eq!(index.query(&region("x", 20000, 30000)), None);
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let path = std::env::temp_dir().join(format!("forgers-test-{}.tbi", std::process::id()));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b"TBI\x01\x01\x00").unwrap();
+        encoder.finish().unwrap();
+        let truncated = Index::load(&path, &header());
+        std::fs::remove_file(&path).unwrap();
+        assert!(truncated.is_err());
+        // the VCF file is no index
+        assert!(Index::load(&VCF, &header()).is_err());
+    }
+
+    #[test]
+    fn test_is_bgzf() {
+        assert!(is_bgzf(&VCF).unwrap());
+        assert!(!is_bgzf(&"test/data/ordered.txt").unwrap());
+    }
+
+    #[test]
+    fn test_indexed_reader() {
+        let header = header();
+        let regions = vec![region("x", 900, 950), region("x", 10, 10)];
+        let mut reader = IndexedReader::open(&VCF, &header, regions, false)
+            .unwrap()
+            .unwrap();
+        let mut record = VCFRecord::new(header);
+        let mut positions = Vec::new();
+        while reader.next_record(&mut record).unwrap() {
+            positions.push(record.position);
+        }
+        assert_eq!(positions, vec![10, 901, 916, 917, 923, 925, 929, 939, 945]);
+    }
+
+    /// Compress a BGZF block.
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let deflated = encoder.finish().unwrap();
+        let mut crc = Crc::new();
+        crc.update(data);
+        let mut block = vec![
+            0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+        ];
+        block.extend_from_slice(&(deflated.len() as u16 + 25).to_le_bytes());
+        block.extend_from_slice(&deflated);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    /// Smallest bin containing the 0-based half-open interval [beg, end).
+    fn reg2bin(beg: u64, end: u64) -> u32 {
+        let end = end - 1;
+        let (mut shift, mut first) = (TBI_MIN_SHIFT, 4681);
+        while shift < TBI_MIN_SHIFT + TBI_DEPTH * 3 && beg >> shift != end >> shift {
+            shift += 3;
+            first = (first - 1) / 8;
+        }
+        (first + (beg >> shift)) as u32
+    }
+
+    /// Write a BGZF-compressed VCF file on contig `x` with each record in its
+    /// own block, and its tabix index.
+    fn write_indexed(path: &Path, sites: &[(u64, usize)]) {
+        let mut data = bgzf_block(
+            b"##fileformat=VCFv4.2\n##contig=<ID=x>\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n",
+        );
+        let mut bins: Vec<(u32, Vec<(u64, u64)>)> = Vec::new();
+        let mut intervals: Vec<u64> = Vec::new();
+        for (pos, ref_len) in sites {
+            let line = format!("x\t{}\t.\t{}\tA\t.\t.\t.\n", pos, "C".repeat(*ref_len));
+            let begin = (data.len() as u64) << 16;
+            data.extend(bgzf_block(line.as_bytes()));
+            let (beg, end) = (pos - 1, pos - 1 + *ref_len as u64);
+            let bin = reg2bin(beg, end);
+            match bins.iter_mut().find(|(b, _)| *b == bin) {
+                Some((_, chunks)) => chunks.push((begin, (data.len() as u64) << 16)),
+                None => bins.push((bin, vec![(begin, (data.len() as u64) << 16)])),
+            }
+            let last = ((end - 1) >> TBI_MIN_SHIFT) as usize;
+            while intervals.len() <= last {
+                intervals.push(begin);
+            }
+        }
+        data.extend(bgzf_block(b""));
+        std::fs::write(path, data).unwrap();
+
+        let mut index = b"TBI\x01".to_vec();
+        let mut push = |v: &[u8]| index.extend_from_slice(v);
+        push(&1i32.to_le_bytes()); // n_ref
+        for v in [2i32, 1, 2, 0, b'#' as i32, 0, 2] {
+            push(&v.to_le_bytes());
+        }
+        push(b"x\0");
+        push(&(bins.len() as i32).to_le_bytes());
+        for (bin, chunks) in bins {
+            push(&bin.to_le_bytes());
+            push(&(chunks.len() as i32).to_le_bytes());
+            for (begin, end) in chunks {
+                push(&begin.to_le_bytes());
+                push(&end.to_le_bytes());
+            }
+        }
+        push(&(intervals.len() as i32).to_le_bytes());
+        for offset in intervals {
+            push(&offset.to_le_bytes());
+        }
+        let file = File::create(format!("{}.tbi", path.display())).unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(&index).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    /// Read the positions of the records from a record source.
+    fn positions<R: RecordRead>(mut reader: R) -> Vec<u64> {
+        let mut record = VCFRecord::new(reader.header().clone());
+        let mut positions = Vec::new();
+        while reader.next_record(&mut record).unwrap() {
+            positions.push(record.position);
+        }
+        positions
+    }
+
+    #[test]
+    fn test_indexed_reader_chain() {
+        let path =
+            std::env::temp_dir().join(format!("forgers-test-{}-chain.vcf.gz", std::process::id()));
+        // a chain of deletions from window 0 reaching into window 2 at 33500
+        let sites = [
+            (50, 1),
+            (100, 10000),
+            (10000, 10000),
+            (19000, 15000),
+            (20000, 1),
+            (33500, 1),
+            (34500, 1),
+        ];
+        write_indexed(&path, &sites);
+        let file = File::open(&path).unwrap();
+        let reader = VCFReader::new(BufReader::new(MultiGzDecoder::new(file))).unwrap();
+        let header = reader.header().clone();
+        let regions = vec![region("x", 33500, 33600)];
+        let mut results = Vec::new();
+        for extend in [false, true] {
+            let file = File::open(&path).unwrap();
+            let reader = VCFReader::new(BufReader::new(MultiGzDecoder::new(file))).unwrap();
+            let streamed = positions(RegionFilter::new(reader, &regions, extend));
+            let indexed = IndexedReader::open(&path, &header, regions.clone(), extend)
+                .unwrap()
+                .unwrap();
+            results.push((streamed, positions(indexed)));
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.tbi", path.display())).unwrap();
+
+        for (streamed, indexed) in &results {
+            assert_eq!(indexed, streamed);
+        }
+        assert_eq!(results[0].0, vec![19000, 33500]);
+        assert_eq!(results[1].0, vec![100, 10000, 19000, 20000, 33500]);
+    }
+}
@@ -0,0 +1,132 @@
+use vcf::{VCFHeader, VCFRecord};
+
+use crate::option::LengthOpt;
+use crate::vcf_util::{extend_header, filter_header_line, filter_values, is_symbolic};
+
+/// Outcome of checking a record against the allele length caps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthCheck {
+    /// The record is within the caps
+    Pass,
+    /// The record is oversized and should be dropped
+    Drop,
+    /// The record is oversized and should be kept with a FILTER tag
+    SoftFilter,
+}
+
+/// Get the length of a symbolic SV allele from INFO/SVLEN or INFO/END.
+fn svlen(record: &VCFRecord) -> Option<u64> {
+    let parse = |v: &[u8]| std::str::from_utf8(v).ok()?.parse::<i64>().ok();
+    if let Some(values) = record.info(b"SVLEN") {
+        return values
+            .iter()
+            .filter_map(|v| parse(v))
+            .map(|v| v.unsigned_abs())
+            .max();
+    }
+    let end = record.info(b"END")?.first().and_then(|v| parse(v))?;
+    Some(end.saturating_sub(record.position as i64).unsigned_abs())
+}
+
+/// Check whether any allele of a record exceeds the length caps.
+///
+/// Symbolic alleles are only checked against the SV length cap; the others
+/// against the ALT allele length cap.
+pub fn is_oversized(record: &VCFRecord, opts: &LengthOpt) -> bool {
+    if opts
+        .max_ref_len
+        .is_some_and(|max| record.reference.len() > max)
+    {
+        return true;
+    }
+    let mut symbolic = false;
+    for allele in record.alternative.iter() {
+        if is_symbolic(allele) {
+            symbolic = true;
+        } else if opts.max_alt_len.is_some_and(|max| allele.len() > max) {
+            return true;
+        }
+    }
+    symbolic
+        && opts
+            .max_svlen
+            .is_some_and(|max| svlen(record).is_some_and(|len| len > max))
+}
+
+/// Check a record against the allele length caps.
+pub fn check(record: &VCFRecord, opts: &LengthOpt) -> LengthCheck {
+    if !is_oversized(record, opts) {
+        LengthCheck::Pass
+    } else if opts.length_filter.is_some() {
+        LengthCheck::SoftFilter
+    } else {
+        LengthCheck::Drop
+    }
+}
+
+/// Add the soft filter ID of oversized records to the FILTER column.
+///
+/// The FILTER values are written back as one ';'-separated entry, since the
+/// VCF writer joins the entries by ','.
+pub fn soft_filter(record: &mut VCFRecord, opts: &LengthOpt) {
+    if let Some(id) = &opts.length_filter {
+        let id = id.as_bytes();
+        let mut values: Vec<&[u8]> = filter_values(record)
+            .filter(|f| *f != b"PASS" && *f != id)
+            .collect();
+        values.push(id);
+        record.filter = vec![values.join(&b';')];
+    }
+}
+
+/// Get the output VCF header with the definition of the soft filter, if any.
+pub fn output_header(header: &VCFHeader, opts: &LengthOpt) -> VCFHeader {
+    match &opts.length_filter {
+        Some(id) if header.filter(id.as_bytes()).is_none() => {
+            let line = filter_header_line(id, "Allele length exceeds the length caps");
+            extend_header(header, vec![line])
+        }
+        _ => header.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::parse_vcf;
+    use vcf::VCFWriter;
+
+    #[test]
+    fn test_soft_filter() {
+        let (header, mut records) = parse_vcf(
+            "##fileformat=VCFv4.2
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+x\t1\t.\tA\tC\t.\t.\t.
+x\t2\t.\tA\tC\t.\tPASS\t.
+x\t3\t.\tA\tC\t.\tq10\t.
+x\t4\t.\tA\tC\t.\tq10;LEN;s50\t.
+",
+        );
+        let opts = LengthOpt {
+            max_ref_len: None,
+            max_alt_len: None,
+            max_svlen: None,
+            length_filter: Some("LEN".to_string()),
+        };
+        let mut output = Vec::new();
+        {
+            let mut writer = VCFWriter::new(&mut output, &header).unwrap();
+            for record in records.iter_mut() {
+                soft_filter(record, &opts);
+                writer.write_record(record).unwrap();
+            }
+        }
+        let filters: Vec<String> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.split('\t').nth(6).unwrap().to_string())
+            .collect();
+        assert_eq!(filters, vec!["LEN", "LEN", "q10;LEN", "q10;s50;LEN"]);
+    }
+}
@@ -1,16 +1,39 @@
+pub mod check;
+pub mod contig;
+pub mod density;
+pub mod expr;
+pub mod fasta;
 pub mod filter;
+pub mod force;
 pub mod forge;
+pub mod group;
+pub mod haplotype;
+pub mod index;
+pub mod length;
+pub mod merge;
 pub mod option;
+pub mod region;
+pub mod report;
 pub mod resolve;
+pub mod samples;
+pub mod screen;
+pub mod sort;
 pub mod vcf_util;
 
 use env_logger::Env;
-use log::info;
+use log::{error, info, warn};
 use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
 use structopt::StructOpt;
-use vcf::{VCFReader, VCFWriter};
+use vcf::{VCFHeader, VCFReader, VCFWriter};
 
-use crate::vcf_util::path_or;
+use crate::contig::{ContigFilter, ContigSelector};
+use crate::force::{ForceList, ForceLists};
+use crate::forge::Top;
+use crate::index::IndexedReader;
+use crate::region::{GenomicRegion, RegionFilter};
+use crate::samples::SampleSelection;
+use crate::vcf_util::{path_or, OutputHeader, RecordRead};
 
 /// Initial the logger and set the verbosity.
 fn init_logger(verbose: bool) {
@@ -18,37 +41,383 @@ fn init_logger(verbose: bool) {
     env_logger::Builder::from_env(Env::default().default_filter_or(level)).init();
 }
 
+/// Open the VCF writer for rejected records if its path is specified.
+fn rejected_writer(path: &Option<PathBuf>, header: &VCFHeader) -> Option<vcf_util::DynWriter> {
+    path.as_ref()
+        .map(|p| match vcf_util::writer_path(p, header, false) {
+            Ok(vcf_writer) => vcf_writer,
+            Err(e) => {
+                error!("{}: '{}'", e, p.display());
+                std::process::exit(1);
+            }
+        })
+}
+
+/// Open a VCF writer for each top value if the output path is a template.
+fn templated_writers(
+    opt: &option::Opt,
+    tops: &[Top],
+    header: &VCFHeader,
+) -> Vec<vcf_util::DynWriter> {
+    if !vcf_util::is_template(&opt.output) {
+        if tops.len() > 1 {
+            error!(
+                "Output path must contain '{}' when multiple top values are given",
+                vcf_util::TOP_PLACEHOLDER
+            );
+            std::process::exit(1);
+        }
+        return Vec::new();
+    }
+    let header = opt.output_header(header);
+    tops.iter()
+        .map(|top| {
+            let path = vcf_util::expand_template(&opt.output, &top.to_string());
+            match vcf_util::writer_path(&path, &header, opt.gzip) {
+                Ok(vcf_writer) => vcf_writer,
+                Err(e) => {
+                    error!("{}: '{}'", e, path.display());
+                    std::process::exit(1);
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parse the regions given by `--regions`; region strings naming a whole
+/// contig, e.g. `HLA-A*01:01`, are taken as the contig.
+fn parse_regions(opt: &option::Opt, contigs: &[Vec<u8>]) -> Vec<GenomicRegion> {
+    opt.regions
+        .iter()
+        .map(|r| match GenomicRegion::parse(r, contigs) {
+            Ok(region) => region,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
+/// Load the regions to restrict the input to, if specified.
+fn load_regions(opt: &option::Opt, header: &VCFHeader) -> Option<Vec<GenomicRegion>> {
+    let mut regions = parse_regions(opt, &region::header_contigs(header));
+    if let Some(path) = &opt.regions_file {
+        match region::load_bed(path) {
+            Ok(v) => regions.extend(v),
+            Err(e) => {
+                error!("{}: '{}'", e, path.display());
+                std::process::exit(1);
+            }
+        }
+    } else if regions.is_empty() {
+        return None;
+    }
+    Some(regions)
+}
+
+/// Load patterns of contigs from a file.
+fn load_patterns(path: &PathBuf) -> Vec<String> {
+    match contig::load_patterns(path) {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            error!("{}: '{}'", e, path.display());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Build the selector of contigs to restrict the input to, if specified.
+fn load_contigs(opt: &option::Opt) -> Option<ContigSelector> {
+    let mut include = opt.contigs.clone();
+    if let Some(path) = &opt.contigs_file {
+        include.extend(load_patterns(path));
+    }
+    let mut exclude = opt.exclude_contigs.clone();
+    if let Some(path) = &opt.exclude_contigs_file {
+        exclude.extend(load_patterns(path));
+    }
+    if opt.primary_only {
+        exclude.extend(contig::NON_PRIMARY_CONTIGS.iter().map(|p| p.to_string()));
+    }
+    let include = if include.is_empty() && opt.contigs_file.is_none() {
+        None
+    } else {
+        Some(include)
+    };
+    if include.is_none() && exclude.is_empty() {
+        return None;
+    }
+    Some(ContigSelector::new(include, exclude))
+}
+
+/// Select the samples counting as evidence of coupling in `resolve`, if
+/// specified.
+fn load_samples(opts: &option::ResolveOpt, header: &VCFHeader) -> Option<Vec<bool>> {
+    let selection = match &opts.samples_file {
+        Some(path) => {
+            let name = path.to_string_lossy();
+            match name.strip_prefix('^') {
+                Some(name) => SampleSelection::with_negation(load_patterns(&name.into()), true),
+                None => SampleSelection::with_negation(load_patterns(path), false),
+            }
+        }
+        None if opts.samples.is_empty() => return None,
+        None => SampleSelection::new(opts.samples.clone()),
+    };
+    match selection.select(header.samples()) {
+        Ok(selected) => Some(selected),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Load the lists of sites forced to be included or excluded, if specified.
+fn load_force_lists(opt: &option::Opt) -> ForceLists {
+    let load = |path: &Option<PathBuf>| match path {
+        Some(path) => match ForceList::load(path) {
+            Ok(list) => {
+                info!(
+                    "Loaded {} forced sites from '{}'",
+                    list.len(),
+                    path.display()
+                );
+                list
+            }
+            Err(e) => {
+                error!("{}: '{}'", e, path.display());
+                std::process::exit(1);
+            }
+        },
+        None => ForceList::default(),
+    };
+    ForceLists {
+        include: load(&opt.force_include),
+        exclude: load(&opt.force_exclude),
+    }
+}
+
+/// Dispatch the function corresponding to each subcommand with required parameters.
+fn dispatch<W, S>(opt: &option::Opt, vcf_writer: VCFWriter<BufWriter<W>>, vcf_reader: S)
+where
+    W: Write,
+    S: RecordRead,
+{
+    let screen = screen::Screen {
+        length: &opt.length,
+        force: load_force_lists(opt),
+        filters: opt
+            .apply_filters
+            .iter()
+            .map(|f| f.as_bytes().to_vec())
+            .collect(),
+        unrank_failed: opt.unrank_failed,
+    };
+    match &opt.cmd {
+        option::Command::Filter(opts) => {
+            let annotation = &opts.annotation;
+            info!("parameter: top\t\t= {:?}", opts.top);
+            info!("parameter: top_snv\t\t= {:?}", opts.top_snv);
+            info!("parameter: top_indel\t= {:?}", opts.top_indel);
+            info!("parameter: top_sv\t\t= {:?}", opts.top_sv);
+            info!("parameter: sv_min_len\t= {}", opts.sv_min_len);
+            if let Some(include) = &opts.include {
+                info!("parameter: include\t\t= {}", include);
+            }
+            if let Some(exclude) = &opts.exclude {
+                info!("parameter: exclude\t\t= {}", exclude);
+            }
+            if let Some(max_per_window) = opts.max_per_window {
+                info!("parameter: max_per_window\t= {}", max_per_window);
+                info!("parameter: window\t\t= {}", opts.window);
+                info!("parameter: window_mode\t= {:?}", opts.window_mode);
+            }
+            if let Some(max_haplotypes) = opts.max_haplotypes {
+                info!("parameter: max_haplotypes\t= {}", max_haplotypes);
+                info!("parameter: haplotype_window\t= {}", opts.haplotype_window);
+            }
+            if let Some(group_by) = &opts.group_by {
+                info!("parameter: group_by\t\t= {:?}", group_by);
+                info!("parameter: group_window\t= {}", opts.group_window);
+                info!("parameter: group_key\t= {}", opts.group_key);
+            }
+            info!("parameter: top_basis\t= {:?}", opts.top_basis);
+            info!("parameter: annotate\t= {}", annotation.annotate);
+            info!("parameter: info_key\t= {}", annotation.info_key);
+            info!(
+                "parameter: annotate_percentile\t= {}",
+                annotation.annotate_percentile
+            );
+            info!("parameter: percentile_key\t= {}", annotation.percentile_key);
+            info!("parameter: annotate_bin\t= {}", annotation.annotate_bin);
+            info!("parameter: bin_key\t= {}", annotation.bin_key);
+            info!("parameter: bins\t\t= {:?}", annotation.bins.0);
+            info!("parameter: annotate_score\t= {}", annotation.annotate_score);
+            info!("parameter: score_key\t= {}", annotation.score_key);
+            info!("parameter: order\t\t= {:?}", opts.order);
+            if let Some(tmpdir) = &opts.tmpdir {
+                info!("parameter: tmpdir\t\t= {}", tmpdir.display());
+            }
+            info!("parameter: sort_buffer\t= {}", opts.sort_buffer);
+            info!("parameter: command\t\t= filter");
+            let rejected = rejected_writer(&opt.rejected_output, vcf_reader.header());
+            let templated = templated_writers(opt, &opts.top, vcf_reader.header());
+            filter::filter(
+                vcf_writer,
+                vcf_reader,
+                &opt.ranks_path,
+                opts,
+                &screen,
+                rejected,
+                templated,
+            )
+            .unwrap();
+        }
+
+        option::Command::Resolve(opts) => {
+            if opts.max_exact > resolve::MAX_EXACT_LIMIT {
+                error!("--max-exact must be at most {}", resolve::MAX_EXACT_LIMIT);
+                std::process::exit(1);
+            }
+            if opts.max_exact > resolve::MAX_EXACT_WARN {
+                warn!(
+                    "--max-exact above {} may take exponential time on dense conflicts",
+                    resolve::MAX_EXACT_WARN
+                );
+            }
+            info!("parameter: max_exact\t= {}", opts.max_exact);
+            info!("parameter: conflict_action\t= {:?}", opts.conflict_action);
+            if opts.conflict_action == option::ConflictAction::Mask {
+                info!("parameter: mask_allele\t= {:?}", opts.mask_allele);
+            }
+            info!("parameter: lenient\t\t= {}", opts.lenient);
+            info!("parameter: missing\t\t= {:?}", opts.missing);
+            info!("parameter: unphased\t= {:?}", opts.unphased);
+            let samples = load_samples(opts, vcf_reader.header());
+            if let Some(selected) = &samples {
+                info!(
+                    "parameter: samples\t\t= {} of {} sample(s)",
+                    selected.iter().filter(|s| **s).count(),
+                    selected.len()
+                );
+                info!("parameter: subset_output\t= {}", opts.subset_output);
+            }
+            if opts.conflict_action == option::ConflictAction::Merge && opts.reference.is_none() {
+                error!("--conflict-action merge requires --reference");
+                std::process::exit(1);
+            }
+            let fasta = opts.reference.as_ref().map(|path| {
+                info!("parameter: reference\t= {}", path.display());
+                match fasta::Fasta::open(path) {
+                    Ok(fasta) => fasta,
+                    Err(e) => {
+                        error!("Cannot open reference '{}': {}", path.display(), e);
+                        std::process::exit(1);
+                    }
+                }
+            });
+            let report = opts.report.as_ref().map(|path| {
+                info!("parameter: report\t\t= {}", path.display());
+                match report::Report::create(path) {
+                    Ok(report) => report,
+                    Err(e) => {
+                        error!("{}: '{}'", e, path.display());
+                        std::process::exit(1);
+                    }
+                }
+            });
+            info!("parameter: command\t\t= resolve");
+            let mut header = resolve::rejected_header(vcf_reader.header(), opts);
+            if let Some(selected) = samples.as_ref().filter(|_| opts.subset_output) {
+                header = samples::subset_header(&header, selected);
+            }
+            let rejected = rejected_writer(&opt.rejected_output, &header);
+            resolve::resolve(
+                vcf_writer,
+                vcf_reader,
+                &opt.ranks_path,
+                opts,
+                &screen,
+                rejected,
+                resolve::Resources {
+                    fasta,
+                    report,
+                    samples,
+                },
+            )
+            .unwrap();
+        }
+    }
+}
+
 impl<W: Write, R: Read> vcf_util::Process<W, R> for option::Opt {
-    /// Dispatch the function corresponding to each subcommand with required parameters.
+    /// Restrict the input to the specified regions, if any, and dispatch the
+    /// subcommand.
     fn process(&mut self, vcf_writer: VCFWriter<BufWriter<W>>, vcf_reader: VCFReader<BufReader<R>>)
     where
         R: Read,
         W: Write,
     {
+        let contigs = load_contigs(self);
+        let regions = match (load_regions(self, vcf_reader.header()), contigs) {
+            (Some(mut regions), contigs) => {
+                if let Some(selector) = contigs {
+                    regions.retain(|r| selector.is_selected(&r.chrom));
+                }
+                regions
+            }
+            (None, Some(selector)) => {
+                if let vcf_util::StreamType::File = vcf_util::stream_type(&self.input) {
+                    match IndexedReader::open_contigs(&self.input, vcf_reader.header(), &selector) {
+                        Ok(Some(indexed_reader)) => {
+                            return dispatch(self, vcf_writer, indexed_reader)
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            error!("{}: '{}'", e, self.input.display());
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                index::warn_no_index(&self.input);
+                let contig_filter = ContigFilter::new(vcf_reader, selector);
+                return dispatch(self, vcf_writer, contig_filter);
+            }
+            (None, None) => return dispatch(self, vcf_writer, vcf_reader),
+        };
+        info!("parameter: regions\t\t= {} region(s)", regions.len());
+        // keep overlapping clusters at region boundaries intact for resolving
+        let extend = matches!(self.cmd, option::Command::Resolve(_));
+        if let vcf_util::StreamType::File = vcf_util::stream_type(&self.input) {
+            match IndexedReader::open(&self.input, vcf_reader.header(), regions.clone(), extend) {
+                Ok(Some(indexed_reader)) => return dispatch(self, vcf_writer, indexed_reader),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("{}: '{}'", e, self.input.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        index::warn_no_index(&self.input);
+        let region_filter = RegionFilter::new(vcf_reader, &regions, extend);
+        dispatch(self, vcf_writer, region_filter);
+    }
+}
+
+impl vcf_util::OutputHeader for option::Opt {
+    /// Get the output VCF header of each subcommand.
+    fn output_header(&self, header: &VCFHeader) -> VCFHeader {
+        let header = &length::output_header(header, &self.length);
         match &self.cmd {
-            option::Command::Filter {
-                top,
-                annotate,
-                info_key,
-            } => {
-                info!("parameter: top\t\t= {}", top);
-                info!("parameter: annotate\t= {}", annotate);
-                info!("parameter: info_key\t= {}", info_key);
-                info!("parameter: command\t\t= filter");
-                filter::filter(
-                    vcf_writer,
-                    vcf_reader,
-                    &self.ranks_path,
-                    *top,
-                    *annotate,
-                    info_key,
-                )
-                .unwrap();
-            }
-
-            option::Command::Resolve {} => {
-                info!("parameter: command\t\t= resolve");
-                resolve::resolve(vcf_writer, vcf_reader, &self.ranks_path).unwrap();
+            option::Command::Filter(opts) => filter::output_header(header, opts),
+            option::Command::Resolve(opts) => {
+                let output = resolve::output_header(header, opts);
+                match load_samples(opts, header).filter(|_| opts.subset_output) {
+                    Some(selected) => samples::subset_header(&output, &selected),
+                    None => output,
+                }
             }
         }
     }
@@ -63,6 +432,63 @@ fn main() {
     info!("parameter: ranks_path\t= {}", &opt.ranks_path.display());
     info!("parameter: gzip\t\t= {}", opt.gzip);
     info!("parameter: output\t\t= {}", path_or(&opt.output, "stdout"));
+    if let Some(rejected_output) = &opt.rejected_output {
+        info!(
+            "parameter: rejected_output\t= {}",
+            rejected_output.display()
+        );
+        if matches!(
+            vcf_util::stream_type(rejected_output),
+            vcf_util::StreamType::Stdio
+        ) && matches!(
+            vcf_util::stream_type(&opt.output),
+            vcf_util::StreamType::Stdio
+        ) {
+            error!("Rejected output cannot be stdout when the output is stdout");
+            std::process::exit(1);
+        }
+    }
+    // fail on invalid regions before writing any output
+    parse_regions(&opt, &[]);
+    if !opt.contigs.is_empty() {
+        info!("parameter: contigs\t\t= {:?}", opt.contigs);
+    }
+    if let Some(contigs_file) = &opt.contigs_file {
+        info!("parameter: contigs_file\t= {}", contigs_file.display());
+    }
+    if !opt.exclude_contigs.is_empty() {
+        info!("parameter: exclude_contigs\t= {:?}", opt.exclude_contigs);
+    }
+    if let Some(exclude_contigs_file) = &opt.exclude_contigs_file {
+        info!(
+            "parameter: exclude_contigs_file\t= {}",
+            exclude_contigs_file.display()
+        );
+    }
+    info!("parameter: primary_only\t= {}", opt.primary_only);
+    if let Some(force_include) = &opt.force_include {
+        info!("parameter: force_include\t= {}", force_include.display());
+    }
+    if let Some(force_exclude) = &opt.force_exclude {
+        info!("parameter: force_exclude\t= {}", force_exclude.display());
+    }
+    if !opt.apply_filters.is_empty() {
+        info!("parameter: apply_filters\t= {:?}", opt.apply_filters);
+        info!("parameter: unrank_failed\t= {}", opt.unrank_failed);
+    }
+    let length = &opt.length;
+    if let Some(max_ref_len) = length.max_ref_len {
+        info!("parameter: max_ref_len\t= {}", max_ref_len);
+    }
+    if let Some(max_alt_len) = length.max_alt_len {
+        info!("parameter: max_alt_len\t= {}", max_alt_len);
+    }
+    if let Some(max_svlen) = length.max_svlen {
+        info!("parameter: max_svlen\t= {}", max_svlen);
+    }
+    if let Some(length_filter) = &length.length_filter {
+        info!("parameter: length_filter\t= {}", length_filter);
+    }
 
     vcf_util::launch_iostream(opt);
 }
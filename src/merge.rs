@@ -0,0 +1,355 @@
+use std::cmp::Reverse;
+use vcf::VCFRecord;
+
+use crate::fasta::Fasta;
+use crate::vcf_util::{is_symbolic, parse_genotype, phase_set, unwrap_genotype, Genotype};
+
+/// Statistics of merging clusters of conflicting records.
+#[derive(Debug, Default)]
+pub struct MergeStats {
+    /// Number of merged records
+    pub merged: usize,
+    /// Number of sample genotypes left missing for undetermined haplotypes
+    pub undetermined: usize,
+    /// Number of alleles left out of a haplotype for overlapping a
+    /// higher-ranked allele on the same haplotype
+    pub skipped: usize,
+}
+
+/// An edit of the reference sequence by an ALT allele in 0-based half-open
+/// coordinates relative to the start of the merged record.
+struct Edit {
+    start: usize,
+    end: usize,
+    seq: Vec<u8>,
+}
+
+impl Edit {
+    /// Get the edit of an ALT allele of a record without the bases shared
+    /// with the REF allele.
+    fn new(record: &VCFRecord, allele: usize, span_start: u64) -> Self {
+        let reference = &record.reference;
+        let alt = &record.alternative[allele - 1];
+        let prefix = reference
+            .iter()
+            .zip(alt.iter())
+            .take_while(|(r, a)| r.eq_ignore_ascii_case(a))
+            .count();
+        let (mut ref_end, mut alt_end) = (reference.len(), alt.len());
+        while ref_end > prefix
+            && alt_end > prefix
+            && reference[ref_end - 1].eq_ignore_ascii_case(&alt[alt_end - 1])
+        {
+            ref_end -= 1;
+            alt_end -= 1;
+        }
+        let offset = (record.position - span_start) as usize;
+        Edit {
+            start: offset + prefix,
+            end: offset + ref_end,
+            seq: alt[prefix..alt_end].to_ascii_uppercase(),
+        }
+    }
+
+    fn is_insertion(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Check whether two edits cannot be applied to one haplotype together.
+    fn overlaps(&self, other: &Edit) -> bool {
+        match (self.is_insertion(), other.is_insertion()) {
+            (true, true) => self.start == other.start,
+            (true, false) => other.start < self.start && self.start < other.end,
+            (false, true) => self.start < other.start && other.start < self.end,
+            (false, false) => self.start < other.end && other.start < self.end,
+        }
+    }
+}
+
+/// Get the alleles of each haplotype of a sample in the records of a cluster.
+///
+/// It returns whether any of them is phased and the alleles of the records on
+/// each haplotype, or `None` if the haplotypes cannot be determined; i.e. for missing or
+/// unphased heterozygous genotypes, different ploidies, or heterozygous
+/// genotypes in different phase sets.
+fn sample_haplotypes(cluster: &[VCFRecord], sample: &[u8]) -> Option<(bool, Vec<Vec<usize>>)> {
+    let mut phased = false;
+    let mut set: Option<&[u8]> = None;
+    let mut alleles: Vec<Vec<usize>> = Vec::new();
+    for record in cluster {
+        let gt = unwrap_genotype(parse_genotype(record.genotype(sample, b"GT")), sample);
+        let (v, is_phased) = match gt {
+            Genotype::Phased(v) => (v, true),
+            Genotype::Unphased(v) if v.iter().all(|a| *a == v[0]) => (v, false),
+            _ => return None,
+        };
+        if alleles.first().is_some_and(|first| first.len() != v.len()) {
+            return None;
+        }
+        if is_phased && v.iter().any(|a| *a != v[0]) {
+            phased = true;
+            let ps = phase_set(record, sample);
+            if set.is_some_and(|s| s != ps) {
+                return None;
+            }
+            set = Some(ps);
+        }
+        alleles.push(v);
+    }
+    let haplotypes = (0..alleles[0].len())
+        .map(|hap| alleles.iter().map(|v| v[hap]).collect())
+        .collect();
+    Some((phased, haplotypes))
+}
+
+/// Merge the records of a cluster into one record whose ALT alleles are the
+/// haplotype sequences observed in the samples.
+///
+/// The merged record spans all records in the cluster and its REF allele is
+/// taken from the reference sequence. The haplotype sequence of each sample is
+/// built by applying the ALT alleles on the haplotype to the REF allele; an
+/// allele overlapping a higher-priority one on the same haplotype is left out.
+/// The genotypes of the samples whose haplotypes cannot be determined (see
+/// [`sample_haplotypes`]) are set to missing. The merged record has only GT
+/// in its FORMAT column, no INFO fields, and the FILTER column of the
+/// highest-priority record.
+///
+/// # Arguments
+///
+/// * `cluster` - Records of the cluster in the input order
+/// * `order` - Indices of the records in the cluster in the order of priority
+/// * `fasta` - Reference sequences
+/// * `stats` - Statistics of merging
+pub fn merge_cluster(
+    cluster: &[VCFRecord],
+    order: &[usize],
+    fasta: &mut Fasta,
+    stats: &mut MergeStats,
+) -> Result<VCFRecord, String> {
+    if cluster
+        .iter()
+        .flat_map(|r| r.alternative.iter())
+        .any(|a| is_symbolic(a) || a == b"*" || a == b".")
+    {
+        return Err("symbolic or missing ALT alleles".to_string());
+    }
+    let chrom = &cluster[0].chromosome;
+    let start = cluster.iter().map(|r| r.position).min().unwrap();
+    let end = cluster
+        .iter()
+        .map(|r| r.position + r.reference.len() as u64 - 1)
+        .max()
+        .unwrap();
+    let reference = fasta.fetch(chrom, start, end).map_err(|e| e.to_string())?;
+    for record in cluster {
+        let offset = (record.position - start) as usize;
+        let expected = &reference[offset..offset + record.reference.len()];
+        if !expected.eq_ignore_ascii_case(&record.reference) {
+            return Err(format!(
+                "REF allele at {}:{} does not match '{}'",
+                String::from_utf8_lossy(chrom),
+                record.position,
+                fasta.path().display()
+            ));
+        }
+    }
+
+    let mut alleles: Vec<Vec<u8>> = vec![reference.clone()];
+    let mut genotypes = Vec::new();
+    for sample in cluster[0].header().samples() {
+        let (phased, haplotypes) = match sample_haplotypes(cluster, sample) {
+            Some(haplotypes) => haplotypes,
+            None => {
+                stats.undetermined += 1;
+                let ploidy = cluster[0]
+                    .genotype(sample, b"GT")
+                    .and_then(|v| v.first())
+                    .map_or(2, |gt| gt.split(|c| *c == b'/' || *c == b'|').count());
+                genotypes.push(vec![&b"."[..]; ploidy].join(&b'/'));
+                continue;
+            }
+        };
+        let mut indices = Vec::new();
+        for alleles_on_hap in haplotypes {
+            let mut applied: Vec<Edit> = Vec::new();
+            for idx in order {
+                let allele = alleles_on_hap[*idx];
+                if allele == 0 {
+                    continue;
+                }
+                let edit = Edit::new(&cluster[*idx], allele, start);
+                if applied.iter().any(|e| e.overlaps(&edit)) {
+                    stats.skipped += 1;
+                    continue;
+                }
+                applied.push(edit);
+            }
+            // apply from right to left; deletions before insertions at their start
+            applied.sort_by_key(|e| Reverse((e.start, e.end)));
+            let mut seq = reference.clone();
+            for edit in applied {
+                seq.splice(edit.start..edit.end, edit.seq);
+            }
+            if seq.is_empty() {
+                return Err("empty haplotype sequence".to_string());
+            }
+            let index = match alleles.iter().position(|a| *a == seq) {
+                Some(index) => index,
+                None => {
+                    alleles.push(seq);
+                    alleles.len() - 1
+                }
+            };
+            indices.push(index.to_string().into_bytes());
+        }
+        let separator = if phased { b'|' } else { b'/' };
+        genotypes.push(indices.join(&separator));
+    }
+    if alleles.len() == 1 {
+        return Err("no ALT allele observed".to_string());
+    }
+
+    let mut merged = VCFRecord::new(cluster[0].header().clone());
+    merged.chromosome = chrom.clone();
+    merged.position = start;
+    merged.id = cluster
+        .iter()
+        .flat_map(|r| r.id.iter())
+        .filter(|id| id.as_slice() != b".")
+        .cloned()
+        .collect();
+    merged.reference = alleles.remove(0);
+    merged.alternative = alleles;
+    merged.filter = cluster[order[0]].filter.clone();
+    merged.format = vec![b"GT".to_vec()];
+    merged.genotype = genotypes.into_iter().map(|gt| vec![vec![gt]]).collect();
+    merged.recreate_info_and_genotype_index();
+    stats.merged += 1;
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta::TempFasta;
+    use crate::vcf_util::parse_vcf;
+
+    const HEADER: &str = "##fileformat=VCFv4.2
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+##FORMAT=<ID=PS,Number=1,Type=Integer,Description=\"Phase set\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2
+";
+
+    /// Reference sequence `x` of the tests.
+    const FASTA: &str = ">x\nACCGTACGTA\n";
+
+    fn merge(name: &str, body: &str, order: &[usize]) -> (Result<VCFRecord, String>, MergeStats) {
+        let temp = TempFasta::new(name, FASTA);
+        let mut fasta = Fasta::open(&temp.0).unwrap();
+        let (_, cluster) = parse_vcf(&format!("{}{}", HEADER, body));
+        let mut stats = MergeStats::default();
+        (
+            merge_cluster(&cluster, order, &mut fasta, &mut stats),
+            stats,
+        )
+    }
+
+    fn genotypes(record: &VCFRecord) -> Vec<String> {
+        record
+            .header()
+            .samples()
+            .iter()
+            .map(|s| String::from_utf8(record.genotype(s, b"GT").unwrap()[0].clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_cluster() {
+        let body = "x\t2\trs1\tC\tT\t.\tPASS\t.\tGT\t1|0\t0|0
+x\t4\trs2\tGTA\tG\t.\tq10\t.\tGT\t0|1\t1|1
+";
+        let (merged, stats) = merge("haps", body, &[1, 0]);
+        let merged = merged.unwrap();
+        assert_eq!(merged.position, 2);
+        assert_eq!(merged.id, vec![b"rs1".to_vec(), b"rs2".to_vec()]);
+        assert_eq!(merged.reference, b"CCGTA");
+        // ALT alleles in the order observed; homozygous samples are unphased
+        assert_eq!(merged.alternative, vec![b"TCGTA".to_vec(), b"CCG".to_vec()]);
+        assert_eq!(genotypes(&merged), vec!["1|2", "2/2"]);
+        // FILTER of the highest-priority record
+        assert_eq!(merged.filter, vec![b"q10".to_vec()]);
+        assert_eq!((stats.merged, stats.undetermined, stats.skipped), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_merge_cluster_undetermined() {
+        // unphased heterozygous and different phase sets
+        let body = "x\t2\t.\tC\tT\t.\t.\t.\tGT:PS\t0/1:1\t1|0:1
+x\t3\t.\tC\tG\t.\t.\t.\tGT:PS\t1/1:1\t0|1:2
+";
+        let (merged, stats) = merge("undetermined", body, &[0, 1]);
+        assert_eq!(merged.unwrap_err(), "no ALT allele observed");
+        assert_eq!(stats.undetermined, 2);
+
+        let body = "x\t2\t.\tC\tT\t.\t.\t.\tGT\t0/1\t1/1
+x\t3\t.\tC\tG\t.\t.\t.\tGT\t0/0\t1/1
+";
+        let (merged, stats) = merge("unphased", body, &[0, 1]);
+        let merged = merged.unwrap();
+        assert_eq!(merged.reference, b"CC");
+        assert_eq!(merged.alternative, vec![b"TG".to_vec()]);
+        assert_eq!(genotypes(&merged), vec!["./.", "1/1"]);
+        assert_eq!(stats.undetermined, 1);
+    }
+
+    #[test]
+    fn test_merge_cluster_missing_phase_set() {
+        // phased genotypes without a PS value share one phase set
+        let body = "x\t2\t.\tC\tT\t.\t.\t.\tGT:PS\t1|0:.\t0|1:.
+x\t3\t.\tC\tG\t.\t.\t.\tGT\t0|1\t0|1
+";
+        let (merged, stats) = merge("missing-ps", body, &[0, 1]);
+        let merged = merged.unwrap();
+        assert_eq!(
+            merged.alternative,
+            vec![b"TC".to_vec(), b"CG".to_vec(), b"TG".to_vec()]
+        );
+        assert_eq!(genotypes(&merged), vec!["1|2", "0|3"]);
+        assert_eq!(stats.undetermined, 0);
+    }
+
+    #[test]
+    fn test_merge_cluster_overlapping_alleles() {
+        // the deletion overlaps the SNV on the first haplotype
+        let body = "x\t3\t.\tCGT\tC\t.\t.\t.\tGT\t1|0\t0|0
+x\t4\t.\tG\tA\t.\t.\t.\tGT\t1|1\t0|0
+";
+        let (merged, stats) = merge("overlapping", body, &[1, 0]);
+        let merged = merged.unwrap();
+        assert_eq!(merged.reference, b"CGT");
+        assert_eq!(merged.alternative, vec![b"CAT".to_vec()]);
+        assert_eq!(genotypes(&merged), vec!["1|1", "0/0"]);
+        assert_eq!(stats.skipped, 1);
+
+        let (merged, _) = merge("overlapping-order", body, &[0, 1]);
+        let merged = merged.unwrap();
+        assert_eq!(merged.alternative, vec![b"C".to_vec(), b"CAT".to_vec()]);
+        assert_eq!(genotypes(&merged), vec!["1|2", "0/0"]);
+    }
+
+    #[test]
+    fn test_merge_cluster_errors() {
+        let body = "x\t2\t.\tG\tT\t.\t.\t.\tGT\t1|0\t0|0
+x\t3\t.\tC\tG\t.\t.\t.\tGT\t0|1\t0|0
+";
+        let (merged, _) = merge("mismatch", body, &[0, 1]);
+        assert!(merged.unwrap_err().starts_with("REF allele at x:2"));
+
+        let body = "x\t2\t.\tC\t<DEL>\t.\t.\t.\tGT\t1|0\t0|0
+x\t3\t.\tC\tG\t.\t.\t.\tGT\t0|1\t0|0
+";
+        let (merged, stats) = merge("symbolic", body, &[0, 1]);
+        assert_eq!(merged.unwrap_err(), "symbolic or missing ALT alleles");
+        assert_eq!(stats.merged, 0);
+    }
+}
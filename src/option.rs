@@ -1,6 +1,11 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::density::WindowMode;
+use crate::expr::Expression;
+use crate::forge::{Bins, Top};
+use crate::group::GroupBy;
+
 /// Data structure for command line options.
 #[derive(Debug, StructOpt)]
 #[structopt(name = "forgers", about = "VCF manipulation based on FORGe ranking.")]
@@ -27,31 +32,449 @@ pub struct Opt {
     #[structopt(short, long, global = true)]
     pub gzip: bool,
 
-    /// Output file, stdout if not specified
+    /// Output file, stdout if not specified; '{top}' in the path is replaced by
+    /// each top value in `filter`
     #[structopt(short, long, global = true, default_value = "-", parse(from_os_str))]
     pub output: PathBuf,
 
+    /// Output file for rejected records, discarded if not specified
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub rejected_output: Option<PathBuf>,
+
+    /// Comma-separated regions (CHROM, CHROM:POS, CHROM:START-, or
+    /// CHROM:START-END) to restrict the input to; uses the input index
+    /// (.tbi/.csi) if available
+    #[structopt(long, global = true, require_delimiter = true)]
+    pub regions: Vec<String>,
+
+    /// BED file of regions to restrict the input to
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub regions_file: Option<PathBuf>,
+
+    /// Comma-separated contig names or glob patterns to restrict the input to
+    #[structopt(long, global = true, require_delimiter = true)]
+    pub contigs: Vec<String>,
+
+    /// File of contig names or glob patterns to restrict the input to; one
+    /// per line
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub contigs_file: Option<PathBuf>,
+
+    /// Comma-separated contig names or glob patterns to exclude from the input
+    #[structopt(long, global = true, require_delimiter = true)]
+    pub exclude_contigs: Vec<String>,
+
+    /// File of contig names or glob patterns to exclude from the input; one
+    /// per line
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub exclude_contigs_file: Option<PathBuf>,
+
+    /// Exclude non-primary contigs; i.e. ALT, decoy, HLA, unplaced, and
+    /// unlocalised contigs
+    #[structopt(long, global = true)]
+    pub primary_only: bool,
+
+    /// VCF or TSV/CSV file of sites (CHROM,POS[,REF,ALT]) to be always
+    /// included regardless of their rank
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub force_include: Option<PathBuf>,
+
+    /// VCF or TSV/CSV file of sites (CHROM,POS[,REF,ALT]) to be always
+    /// excluded regardless of their rank
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub force_exclude: Option<PathBuf>,
+
+    /// Comma-separated FILTER values to keep records with, e.g. 'PASS,.';
+    /// records with none of them are dropped
+    #[structopt(long, global = true, require_delimiter = true)]
+    pub apply_filters: Vec<String>,
+
+    /// Treat records failing `--apply-filters` as unranked instead of
+    /// dropping them
+    #[structopt(long, global = true)]
+    pub unrank_failed: bool,
+
+    #[structopt(flatten)]
+    pub length: LengthOpt,
+
     #[structopt(subcommand)]
     pub cmd: Command,
 }
 
+// Allele length caps, flattened into `Opt`; a doc comment here would replace
+// the `about` text of the command.
+#[derive(Debug, StructOpt)]
+pub struct LengthOpt {
+    /// Maximum length of REF allele; longer records are dropped before
+    /// ranking and resolving
+    #[structopt(long, global = true)]
+    pub max_ref_len: Option<usize>,
+
+    /// Maximum length of non-symbolic ALT alleles; longer records are dropped
+    /// before ranking and resolving
+    #[structopt(long, global = true)]
+    pub max_alt_len: Option<usize>,
+
+    /// Maximum SV length of symbolic ALT alleles (INFO/SVLEN or INFO/END);
+    /// longer records are dropped before ranking and resolving
+    #[structopt(long, global = true)]
+    pub max_svlen: Option<u64>,
+
+    /// Keep oversized records unranked and add this ID to their FILTER column
+    /// instead of dropping them
+    #[structopt(long, global = true)]
+    pub length_filter: Option<String>,
+}
+
 #[derive(Debug, StructOpt)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     #[structopt(name = "filter")]
     /// Filter VCF records based on FORGe ranking
-    Filter {
-        /// Top fraction of records to keep, keeps all by default
-        #[structopt(short, long, default_value = "1.0")]
-        top: f64,
-
-        /// Annotate the filtered records with FORGe rank
-        #[structopt(short, long)]
-        annotate: bool,
-
-        /// Annotate key for INFO field
-        #[structopt(short = "k", long, default_value = "FORGE")]
-        info_key: String,
-    },
+    Filter(FilterOpt),
     /// Resolve overlapping variants based on FORGe ranking
-    Resolve {},
+    Resolve(ResolveOpt),
+}
+
+/// Options of `filter` subcommand.
+#[derive(Debug, StructOpt)]
+pub struct FilterOpt {
+    /// Comma-separated top fractions in [0, 1] or counts of records to keep,
+    /// keeps all by default; output path must contain '{top}' when multiple
+    /// values are given
+    #[structopt(short, long, default_value = "1.0", require_delimiter = true)]
+    pub top: Vec<Top>,
+
+    /// Top fraction or count of SNV/MNP records to keep among the ranked SNVs
+    /// in the input, `--top` if not specified
+    #[structopt(long)]
+    pub top_snv: Option<Top>,
+
+    /// Top fraction or count of indel records to keep among the ranked indels
+    /// in the input, `--top` if not specified
+    #[structopt(long)]
+    pub top_indel: Option<Top>,
+
+    /// Top fraction or count of SV records to keep among the ranked SVs in the
+    /// input, `--top` if not specified
+    #[structopt(long)]
+    pub top_sv: Option<Top>,
+
+    /// Minimum length difference of REF and ALT alleles for a non-symbolic
+    /// variant to be considered as SV
+    #[structopt(long, default_value = "50")]
+    pub sv_min_len: usize,
+
+    /// Keep only the records for which the expression is true; e.g.
+    /// 'FORGE <= 50000 && INFO/AF > 0.01 && TYPE == "snp"'
+    #[structopt(short, long)]
+    pub include: Option<Expression>,
+
+    /// Drop the records for which the expression is true
+    #[structopt(short, long)]
+    pub exclude: Option<Expression>,
+
+    /// Maximum number of records in every window of `--window` bp; the
+    /// best-ranked ones are kept
+    #[structopt(long)]
+    pub max_per_window: Option<usize>,
+
+    /// Window size in bp
+    #[structopt(long, default_value = "1000")]
+    pub window: u64,
+
+    /// Windows of `--max-per-window`: 'sliding' (every window of the size) or
+    /// 'tiled' (non-overlapping windows)
+    #[structopt(long, default_value = "sliding")]
+    pub window_mode: WindowMode,
+
+    /// Maximum number of distinct haplotypes of phased samples in every
+    /// window of `--haplotype-window` bp; the lowest-ranked records are
+    /// removed until the windows are under the cap
+    #[structopt(long)]
+    pub max_haplotypes: Option<usize>,
+
+    /// Window size in bp for `--max-haplotypes`; e.g. k-mer size of the graph
+    /// index
+    #[structopt(long, default_value = "32")]
+    pub haplotype_window: u64,
+
+    /// Link records into groups kept or dropped together with the best rank
+    /// among them: 'PS' (sharing FORMAT/PS in any sample), 'INFO/<key>'
+    /// (sharing the INFO value), or 'window' (chained within
+    /// `--group-window` bp). Not combinable with the density and haplotype
+    /// caps, which would split groups
+    #[structopt(long, conflicts_with_all = &["max-per-window", "max-haplotypes"])]
+    pub group_by: Option<GroupBy>,
+
+    /// Maximum distance in bp between consecutive records linked by
+    /// `--group-by window`
+    #[structopt(long, default_value = "10")]
+    pub group_window: u64,
+
+    /// Annotate key for the group ID INFO field of the linked records
+    #[structopt(long, default_value = "FORGE_GROUP")]
+    pub group_key: String,
+
+    /// What top fractions refer to: 'rank' (entries in the rank file) or
+    /// 'vcf' (ranked records in the input)
+    #[structopt(long, default_value = "rank")]
+    pub top_basis: TopBasis,
+
+    #[structopt(flatten)]
+    pub annotation: AnnotateOpt,
+
+    /// Order of output records: 'coord' (input order) or 'rank' (FORGe rank)
+    #[structopt(long, default_value = "coord")]
+    pub order: Order,
+
+    /// Directory for temporary files, system default if not specified
+    #[structopt(long, parse(from_os_str))]
+    pub tmpdir: Option<PathBuf>,
+
+    /// Maximum number of records held in memory when sorting by rank; shared
+    /// evenly by the outputs of multiple top values
+    #[structopt(long, default_value = "1000000")]
+    pub sort_buffer: usize,
+}
+
+/// Options of `resolve` subcommand.
+#[derive(Debug, StructOpt)]
+pub struct ResolveOpt {
+    /// Maximum number of alleles in a group of conflicting alleles to find the
+    /// best set to keep exactly (at most 64; above 32 may take exponential
+    /// time); larger groups are resolved greedily by rank
+    #[structopt(long, default_value = "20")]
+    pub max_exact: usize,
+
+    /// Action on conflicting alleles: 'drop' (remove the lower-ranked
+    /// records), 'mask' (mask the lower-ranked alleles only on the
+    /// conflicting haplotypes), or 'merge' (merge conflicting records into one
+    /// with the observed haplotype sequences; requires `--reference`)
+    #[structopt(long, default_value = "drop")]
+    pub conflict_action: ConflictAction,
+
+    /// Allele set in place of masked ones by `--conflict-action mask`: 'ref'
+    /// or 'missing'
+    #[structopt(long, default_value = "ref")]
+    pub mask_allele: MaskAllele,
+
+    /// Reference FASTA file, uncompressed and optionally indexed (.fai); used
+    /// for merging and for checking REF alleles and left alignment of indels
+    #[structopt(long, parse(from_os_str))]
+    pub reference: Option<PathBuf>,
+
+    /// Warn about unsorted or non-normalised input records and resolve them
+    /// as they are instead of failing
+    #[structopt(long)]
+    pub lenient: bool,
+
+    /// Report of the resolved clusters with their members, conflicts, and
+    /// decisions; in JSON if the path ends with '.json', and in TSV otherwise
+    #[structopt(long, parse(from_os_str))]
+    pub report: Option<PathBuf>,
+
+    /// Coupling of two alleles in a sample with a missing genotype: 'coupled'
+    /// (missing genotypes are assumed to carry the allele), 'uncoupled', or
+    /// 'skip' (the sample is no evidence either way)
+    #[structopt(long, default_value = "coupled")]
+    pub missing: MissingPolicy,
+
+    /// Coupling of two alleles in a sample whose haplotypes cannot be
+    /// compared; i.e. unphased genotypes or different phase sets: 'coupled'
+    /// (if both alleles are present), 'het-rule' (if implied by the allele
+    /// counts, e.g. a homozygous allele), or 'skip' (the sample is no
+    /// evidence either way)
+    #[structopt(long, default_value = "coupled")]
+    pub unphased: UnphasedPolicy,
+
+    /// Comma-separated samples counting as evidence of coupling; all but
+    /// these if the list starts with '^'
+    #[structopt(long, require_delimiter = true, conflicts_with = "samples-file")]
+    pub samples: Vec<String>,
+
+    /// File of samples counting as evidence of coupling; one per line. All
+    /// but these if the path starts with '^'
+    #[structopt(long, parse(from_os_str))]
+    pub samples_file: Option<PathBuf>,
+
+    /// Restrict the genotype columns of the output to the samples given by
+    /// `--samples` or `--samples-file`
+    #[structopt(long)]
+    pub subset_output: bool,
+}
+
+/// Action on conflicting alleles in resolving.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictAction {
+    Drop,
+    Mask,
+    Merge,
+}
+
+impl std::str::FromStr for ConflictAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(ConflictAction::Drop),
+            "mask" => Ok(ConflictAction::Mask),
+            "merge" => Ok(ConflictAction::Merge),
+            _ => Err(format!(
+                "invalid conflict action '{}': expected 'drop', 'mask', or 'merge'",
+                s
+            )),
+        }
+    }
+}
+
+/// Allele set in place of masked ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaskAllele {
+    Ref,
+    Missing,
+}
+
+impl std::str::FromStr for MaskAllele {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ref" => Ok(MaskAllele::Ref),
+            "missing" => Ok(MaskAllele::Missing),
+            _ => Err(format!(
+                "invalid mask allele '{}': expected 'ref' or 'missing'",
+                s
+            )),
+        }
+    }
+}
+
+/// Coupling policy for samples with missing genotypes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingPolicy {
+    Coupled,
+    Uncoupled,
+    Skip,
+}
+
+impl std::str::FromStr for MissingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coupled" => Ok(MissingPolicy::Coupled),
+            "uncoupled" => Ok(MissingPolicy::Uncoupled),
+            "skip" => Ok(MissingPolicy::Skip),
+            _ => Err(format!(
+                "invalid missing genotype policy '{}': expected 'coupled', 'uncoupled', or 'skip'",
+                s
+            )),
+        }
+    }
+}
+
+/// Coupling policy for samples with unphased genotypes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnphasedPolicy {
+    Coupled,
+    HetRule,
+    Skip,
+}
+
+impl std::str::FromStr for UnphasedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coupled" => Ok(UnphasedPolicy::Coupled),
+            "het-rule" => Ok(UnphasedPolicy::HetRule),
+            "skip" => Ok(UnphasedPolicy::Skip),
+            _ => Err(format!(
+                "invalid unphased genotype policy '{}': expected 'coupled', 'het-rule', or 'skip'",
+                s
+            )),
+        }
+    }
+}
+
+/// Basis of top fractions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopBasis {
+    Rank,
+    Vcf,
+}
+
+impl std::str::FromStr for TopBasis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rank" => Ok(TopBasis::Rank),
+            "vcf" => Ok(TopBasis::Vcf),
+            _ => Err(format!(
+                "invalid top basis '{}': expected 'rank' or 'vcf'",
+                s
+            )),
+        }
+    }
+}
+
+/// Order of output records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Coord,
+    Rank,
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coord" => Ok(Order::Coord),
+            "rank" => Ok(Order::Rank),
+            _ => Err(format!("invalid order '{}': expected 'coord' or 'rank'", s)),
+        }
+    }
+}
+
+/// Options for annotating records with FORGe ranking.
+#[derive(Debug, StructOpt)]
+pub struct AnnotateOpt {
+    /// Annotate the filtered records with FORGe rank
+    #[structopt(short, long)]
+    pub annotate: bool,
+
+    /// Annotate key for INFO field
+    #[structopt(short = "k", long, default_value = "FORGE")]
+    pub info_key: String,
+
+    /// Annotate the filtered records with FORGe percentile (rank / total)
+    #[structopt(long)]
+    pub annotate_percentile: bool,
+
+    /// Annotate key for FORGe percentile INFO field
+    #[structopt(long, default_value = "FORGE_PCT")]
+    pub percentile_key: String,
+
+    /// Annotate the filtered records with their top fraction bin
+    #[structopt(long)]
+    pub annotate_bin: bool,
+
+    /// Annotate key for top fraction bin INFO field
+    #[structopt(long, default_value = "FORGE_BIN")]
+    pub bin_key: String,
+
+    /// Comma-separated top fraction bins in ascending order
+    #[structopt(long, default_value = "0.01,0.05,0.1,0.2,0.5,1.0")]
+    pub bins: Bins,
+
+    /// Annotate the filtered records with FORGe score, if the rank file has one
+    #[structopt(long)]
+    pub annotate_score: bool,
+
+    /// Annotate key for FORGe score INFO field
+    #[structopt(long, default_value = "FORGE_SCORE")]
+    pub score_key: String,
 }
@@ -0,0 +1,381 @@
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use vcf::{VCFError, VCFHeader, VCFRecord};
+
+use crate::vcf_util::RecordRead;
+
+/// A genomic region on a contig.
+///
+/// **NOTE**: The positions are 1-based and the range is inclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenomicRegion {
+    pub chrom: Vec<u8>,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl std::str::FromStr for GenomicRegion {
+    type Err = String;
+
+    /// Parse a region in `CHROM`, `CHROM:POS`, `CHROM:START-`, or
+    /// `CHROM:START-END` format.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let whole = GenomicRegion {
+            chrom: s.as_bytes().to_vec(),
+            start: 1,
+            end: u64::MAX,
+        };
+        let (chrom, range) = match s.rsplit_once(':') {
+            Some(v) => v,
+            None => return Ok(whole),
+        };
+        let parse = |x: &str| x.replace(',', "").parse::<u64>();
+        let (start, end) = match range.split_once('-') {
+            Some((start, "")) => (parse(start), Ok(u64::MAX)),
+            Some((start, end)) => (parse(start), parse(end)),
+            None => (parse(range), parse(range)),
+        };
+        match (start, end) {
+            (Ok(start), Ok(end)) if start <= end => Ok(GenomicRegion {
+                chrom: chrom.as_bytes().to_vec(),
+                start: std::cmp::max(start, 1),
+                end,
+            }),
+            (Ok(_), Ok(_)) => Err(format!("invalid region '{}': start after end", s)),
+            // a contig name containing ':'
+            _ => Ok(whole),
+        }
+    }
+}
+
+impl GenomicRegion {
+    /// Parse a region as in [`std::str::FromStr`] unless the whole string is
+    /// one of the given contig names, e.g. `HLA-A*01:01`.
+    pub fn parse(s: &str, contigs: &[Vec<u8>]) -> Result<Self, String> {
+        if contigs.iter().any(|c| c == s.as_bytes()) {
+            return Ok(GenomicRegion {
+                chrom: s.as_bytes().to_vec(),
+                start: 1,
+                end: u64::MAX,
+            });
+        }
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for GenomicRegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let chrom = String::from_utf8_lossy(&self.chrom);
+        if self.end == u64::MAX {
+            write!(f, "{}:{}-", chrom, self.start)
+        } else {
+            write!(f, "{}:{}-{}", chrom, self.start, self.end)
+        }
+    }
+}
+
+/// Load regions from a BED file.
+///
+/// **NOTE**: BED intervals are 0-based and half-open; they are converted to
+/// 1-based inclusive regions.
+pub fn load_bed<T>(path: &T) -> Result<Vec<GenomicRegion>, std::io::Error>
+where
+    T: AsRef<Path>,
+{
+    let reader = BufReader::new(File::open(path)?);
+    let mut regions = Vec::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let parsed = match fields.as_slice() {
+            [chrom, start, end, ..] => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start < end => Some(GenomicRegion {
+                    chrom: chrom.as_bytes().to_vec(),
+                    start: start + 1,
+                    end,
+                }),
+                _ => None,
+            },
+            _ => None,
+        };
+        match parsed {
+            Some(region) => regions.push(region),
+            None => warn!(
+                "Skipping invalid BED record (line {}) in '{}'",
+                idx + 1,
+                path.as_ref().display()
+            ),
+        }
+    }
+    Ok(regions)
+}
+
+/// Sort regions by contig order and position, and merge the overlapping or
+/// adjacent ones.
+///
+/// # Arguments
+///
+/// `regions` - regions to be merged
+/// `contigs` - contig names in the order of the input file
+///
+/// Regions on the contigs not in `contigs` are discarded.
+pub fn merge_regions(mut regions: Vec<GenomicRegion>, contigs: &[Vec<u8>]) -> Vec<GenomicRegion> {
+    let order: HashMap<&[u8], usize> = contigs
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.as_slice(), i))
+        .collect();
+    regions.retain(|r| {
+        let known = order.contains_key(r.chrom.as_slice());
+        if !known {
+            warn!("No records on the contig of region '{}'", r);
+        }
+        known
+    });
+    regions.sort_by_key(|r| (order[r.chrom.as_slice()], r.start, r.end));
+    let mut merged: Vec<GenomicRegion> = Vec::new();
+    for region in regions {
+        match merged.last_mut() {
+            Some(last)
+                if last.chrom == region.chrom && region.start <= last.end.saturating_add(1) =>
+            {
+                last.end = std::cmp::max(last.end, region.end);
+            }
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+/// Get contig names in the order of their definition in the VCF header.
+pub fn header_contigs(header: &VCFHeader) -> Vec<Vec<u8>> {
+    header
+        .items()
+        .iter()
+        .filter_map(|item| match item.contents() {
+            vcf::VCFHeaderContent::Contig { id, .. } => Some(id.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Inclusive range of reference positions spanned by a record.
+pub fn record_end(record: &VCFRecord) -> u64 {
+    record.position + std::cmp::max(record.reference.len(), 1) as u64 - 1
+}
+
+/// Selector of records overlapping a set of regions.
+///
+/// Records must be fed in the order of the input file. When extension of
+/// clusters is enabled, the selection is extended to the whole chain of
+/// overlapping records containing a selected record; so that overlapping
+/// clusters at region boundaries are kept intact.
+pub struct RegionSelector {
+    regions: HashMap<Vec<u8>, Vec<(u64, u64)>>,
+    extend: bool,
+    pending: Vec<VCFRecord>,
+    pending_end: u64,
+    reach: Option<(Vec<u8>, u64)>,
+}
+
+impl RegionSelector {
+    pub fn new(regions: &[GenomicRegion], extend: bool) -> Self {
+        let mut map: HashMap<Vec<u8>, Vec<(u64, u64)>> = HashMap::new();
+        for region in regions {
+            map.entry(region.chrom.clone())
+                .or_default()
+                .push((region.start, region.end));
+        }
+        for ranges in map.values_mut() {
+            ranges.sort();
+            ranges.dedup_by(|next, prev| {
+                let merge = next.0 <= prev.1.saturating_add(1);
+                if merge {
+                    prev.1 = std::cmp::max(prev.1, next.1);
+                }
+                merge
+            });
+        }
+        RegionSelector {
+            regions: map,
+            extend,
+            pending: Vec::new(),
+            pending_end: 0,
+            reach: None,
+        }
+    }
+
+    /// Check whether the range overlaps any region.
+    fn overlaps(&self, chrom: &[u8], start: u64, end: u64) -> bool {
+        match self.regions.get(chrom) {
+            Some(ranges) => {
+                // first region ending at or after `start`
+                let idx = ranges.partition_point(|r| r.1 < start);
+                ranges[idx..]
+                    .iter()
+                    .take_while(|r| r.0 <= end)
+                    .any(|r| r.1 >= start)
+            }
+            None => false,
+        }
+    }
+
+    /// Feed the next record; push the selected records into `selected`.
+    pub fn feed(&mut self, record: &VCFRecord, selected: &mut VecDeque<VCFRecord>) {
+        let start = record.position;
+        let end = record_end(record);
+        if let Some((chrom, reach)) = self.reach.as_mut() {
+            if self.extend && *chrom == record.chromosome && start <= *reach {
+                *reach = std::cmp::max(*reach, end);
+                selected.push_back(record.clone());
+                return;
+            }
+        }
+        let chained = self
+            .pending
+            .last()
+            .is_some_and(|last| last.chromosome == record.chromosome && start <= self.pending_end);
+        if self.overlaps(&record.chromosome, start, end) {
+            let mut reach = end;
+            if chained {
+                reach = std::cmp::max(reach, self.pending_end);
+                selected.extend(self.pending.drain(..));
+            }
+            self.pending.clear();
+            self.reach = Some((record.chromosome.clone(), reach));
+            selected.push_back(record.clone());
+        } else if self.extend {
+            if !chained {
+                self.pending.clear();
+                self.pending_end = 0;
+            }
+            self.pending_end = std::cmp::max(self.pending_end, end);
+            self.pending.push(record.clone());
+        }
+    }
+
+    /// Check whether no record after the given position can be selected on
+    /// the same contig anymore.
+    pub fn is_passed(&self, chrom: &[u8], pos: u64, region: &GenomicRegion) -> bool {
+        if chrom != region.chrom.as_slice() {
+            return false;
+        }
+        let reach = match &self.reach {
+            Some((c, reach)) if self.extend && c.as_slice() == chrom => *reach,
+            _ => 0,
+        };
+        pos > region.end && pos > reach
+    }
+}
+
+/// Record source selecting the records overlapping a set of regions by
+/// streaming the whole input.
+///
+/// It is used when no index is available for random access.
+pub struct RegionFilter<S: RecordRead> {
+    source: S,
+    selector: RegionSelector,
+    selected: VecDeque<VCFRecord>,
+    record: VCFRecord,
+}
+
+impl<S: RecordRead> RegionFilter<S> {
+    pub fn new(source: S, regions: &[GenomicRegion], extend: bool) -> Self {
+        let record = VCFRecord::new(source.header().clone());
+        RegionFilter {
+            source,
+            selector: RegionSelector::new(regions, extend),
+            selected: VecDeque::new(),
+            record,
+        }
+    }
+}
+
+impl<S: RecordRead> RecordRead for RegionFilter<S> {
+    fn header(&self) -> &VCFHeader {
+        self.source.header()
+    }
+
+    fn next_record(&mut self, record: &mut VCFRecord) -> Result<bool, VCFError> {
+        while self.selected.is_empty() {
+            if !self.source.next_record(&mut self.record)? {
+                return Ok(false);
+            }
+            self.selector.feed(&self.record, &mut self.selected);
+        }
+        *record = self.selected.pop_front().unwrap();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(chrom: &str, start: u64, end: u64) -> GenomicRegion {
+        GenomicRegion {
+            chrom: chrom.as_bytes().to_vec(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_parse_region() {
+        assert_eq!("chr1".parse(), Ok(region("chr1", 1, u64::MAX)));
+        assert_eq!("chr1:100".parse(), Ok(region("chr1", 100, 100)));
+        assert_eq!("chr1:100-".parse(), Ok(region("chr1", 100, u64::MAX)));
+        assert_eq!("chr1:1,000-2,000".parse(), Ok(region("chr1", 1000, 2000)));
+        assert_eq!("chr1:0-5".parse(), Ok(region("chr1", 1, 5)));
+        assert!("chr1:5-1".parse::<GenomicRegion>().is_err());
+        // suffixes not parsed as a range are part of the contig name
+        assert_eq!("a:b".parse(), Ok(region("a:b", 1, u64::MAX)));
+        assert_eq!("a:b:10-20".parse(), Ok(region("a:b", 10, 20)));
+    }
+
+    #[test]
+    fn test_parse_region_with_contigs() {
+        let contigs = vec![b"HLA-A*01:01".to_vec(), b"chr1".to_vec()];
+        assert_eq!(
+            GenomicRegion::parse("HLA-A*01:01", &contigs),
+            Ok(region("HLA-A*01:01", 1, u64::MAX))
+        );
+        assert_eq!(
+            GenomicRegion::parse("HLA-A*01:01:5-9", &contigs),
+            Ok(region("HLA-A*01:01", 5, 9))
+        );
+        assert_eq!(
+            GenomicRegion::parse("chr1:5", &contigs),
+            Ok(region("chr1", 5, 5))
+        );
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        let contigs = vec![b"b".to_vec(), b"a".to_vec()];
+        let merged = merge_regions(
+            vec![
+                region("a", 10, 20),
+                region("b", 5, 6),
+                region("a", 21, 30),
+                region("a", 1, 2),
+                region("c", 1, 2),
+            ],
+            &contigs,
+        );
+        assert_eq!(
+            merged,
+            vec![region("b", 5, 6), region("a", 1, 2), region("a", 10, 30)]
+        );
+    }
+}
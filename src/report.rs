@@ -0,0 +1,354 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::Path;
+
+/// Format of the resolve report, detected by file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// One line per ALT allele of each cluster member
+    Tsv,
+    /// An array of clusters with their members and conflicts
+    Json,
+}
+
+/// Status of an ALT allele after resolving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Written to the output
+    Selected,
+    /// Written to the output unresolved with a FILTER tag
+    Passed,
+    /// Written to the rejected output
+    Rejected,
+    /// Merged with the other alleles of the cluster into one record
+    Merged,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Selected => "selected",
+            Status::Passed => "passed",
+            Status::Rejected => "rejected",
+            Status::Merged => "merged",
+        }
+    }
+}
+
+/// Report of an ALT allele of a cluster member.
+pub struct AlleleReport {
+    pub alt: String,
+    pub status: Status,
+    pub reason: String,
+}
+
+/// Report of a record in a cluster.
+pub struct MemberReport {
+    /// Record identified by CHROM:POS:REF:ALT
+    pub record: String,
+    /// FORGe rank; `None` for unranked records
+    pub rank: Option<usize>,
+    pub alleles: Vec<AlleleReport>,
+}
+
+/// Conflict between two ALT alleles of a cluster.
+///
+/// Alleles are given as (member index, ALT allele index starting from 1).
+pub struct Conflict {
+    pub first: (usize, usize),
+    pub second: (usize, usize),
+    /// Sample in which the alleles are coupled; `None` if they are assumed
+    /// coupled for lack of evidence
+    pub sample: Option<String>,
+}
+
+/// Report of an overlap cluster.
+pub struct ClusterReport {
+    pub id: usize,
+    pub chrom: String,
+    /// Start of the range of competing records, 1-based
+    pub start: u64,
+    /// End of the range of competing records, 1-based and inclusive
+    pub end: u64,
+    pub members: Vec<MemberReport>,
+    pub conflicts: Vec<Conflict>,
+}
+
+const TSV_HEADER: &str =
+    "#cluster\tchrom\tstart\tend\tmember\trecord\trank\talt\tstatus\treason\tconflicts";
+
+/// Writer of the resolve report.
+pub struct Report {
+    writer: BufWriter<File>,
+    format: ReportFormat,
+    nof_clusters: usize,
+}
+
+impl Report {
+    /// Create a report file; in JSON if the path ends with `.json`, and in
+    /// TSV otherwise.
+    pub fn create<T>(path: &T) -> Result<Self, Error>
+    where
+        T: AsRef<Path>,
+    {
+        let format = match path.as_ref().extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ReportFormat::Json,
+            _ => ReportFormat::Tsv,
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format {
+            ReportFormat::Tsv => writeln!(writer, "{}", TSV_HEADER)?,
+            ReportFormat::Json => write!(writer, "[")?,
+        }
+        Ok(Report {
+            writer,
+            format,
+            nof_clusters: 0,
+        })
+    }
+
+    /// Write the report of a cluster.
+    pub fn write(&mut self, cluster: &ClusterReport) -> Result<(), Error> {
+        match self.format {
+            ReportFormat::Tsv => self.write_tsv(cluster)?,
+            ReportFormat::Json => self.write_json(cluster)?,
+        }
+        self.nof_clusters += 1;
+        Ok(())
+    }
+
+    /// Finish the report and flush it.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.format == ReportFormat::Json {
+            if self.nof_clusters != 0 {
+                writeln!(self.writer)?;
+            }
+            writeln!(self.writer, "]")?;
+        }
+        self.writer.flush()
+    }
+
+    fn write_tsv(&mut self, cluster: &ClusterReport) -> Result<(), Error> {
+        for (index, member) in cluster.members.iter().enumerate() {
+            for (allele, report) in member.alleles.iter().enumerate() {
+                let unit = (index, allele + 1);
+                let conflicts: Vec<String> = cluster
+                    .conflicts
+                    .iter()
+                    .filter_map(|c| match unit {
+                        u if u == c.first => Some((c.second, &c.sample)),
+                        u if u == c.second => Some((c.first, &c.sample)),
+                        _ => None,
+                    })
+                    .map(|((m, a), sample)| {
+                        format!("{}.{}@{}", m, a, sample.as_deref().unwrap_or("."))
+                    })
+                    .collect();
+                writeln!(
+                    self.writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    cluster.id,
+                    cluster.chrom,
+                    cluster.start,
+                    cluster.end,
+                    index,
+                    member.record,
+                    member.rank.map_or(".".to_string(), |r| r.to_string()),
+                    report.alt,
+                    report.status.name(),
+                    report.reason,
+                    match conflicts.is_empty() {
+                        true => ".".to_string(),
+                        false => conflicts.join(","),
+                    }
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_json(&mut self, cluster: &ClusterReport) -> Result<(), Error> {
+        let members: Vec<String> = cluster
+            .members
+            .iter()
+            .map(|member| {
+                let alleles: Vec<String> = member
+                    .alleles
+                    .iter()
+                    .map(|a| {
+                        format!(
+                            "{{\"alt\":{},\"status\":\"{}\",\"reason\":{}}}",
+                            json_string(&a.alt),
+                            a.status.name(),
+                            json_string(&a.reason)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"record\":{},\"rank\":{},\"alleles\":[{}]}}",
+                    json_string(&member.record),
+                    member.rank.map_or("null".to_string(), |r| r.to_string()),
+                    alleles.join(",")
+                )
+            })
+            .collect();
+        let conflicts: Vec<String> = cluster
+            .conflicts
+            .iter()
+            .map(|c| {
+                format!(
+                    "{{\"first\":[{},{}],\"second\":[{},{}],\"sample\":{}}}",
+                    c.first.0,
+                    c.first.1,
+                    c.second.0,
+                    c.second.1,
+                    c.sample.as_deref().map_or("null".to_string(), json_string)
+                )
+            })
+            .collect();
+        if self.nof_clusters != 0 {
+            write!(self.writer, ",")?;
+        }
+        write!(
+            self.writer,
+            "\n{{\"cluster\":{},\"chrom\":{},\"start\":{},\"end\":{},\"members\":[{}],\"conflicts\":[{}]}}",
+            cluster.id,
+            json_string(&cluster.chrom),
+            cluster.start,
+            cluster.end,
+            members.join(","),
+            conflicts.join(",")
+        )
+    }
+}
+
+/// Quote a string as a JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allele(alt: &str, status: Status, reason: &str) -> AlleleReport {
+        AlleleReport {
+            alt: alt.to_string(),
+            status,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn cluster(id: usize) -> ClusterReport {
+        ClusterReport {
+            id,
+            chrom: "x".to_string(),
+            start: 10,
+            end: 11,
+            members: vec![
+                MemberReport {
+                    record: "x:10:A:C,G".to_string(),
+                    rank: Some(3),
+                    alleles: vec![
+                        allele("C", Status::Selected, "won conflicts"),
+                        allele("G", Status::Rejected, "beaten by x:11:C:T"),
+                    ],
+                },
+                MemberReport {
+                    record: "x:11:C:T".to_string(),
+                    rank: None,
+                    alleles: vec![allele("T", Status::Selected, "won conflicts")],
+                },
+            ],
+            conflicts: vec![
+                Conflict {
+                    first: (0, 1),
+                    second: (1, 1),
+                    sample: Some("s1".to_string()),
+                },
+                Conflict {
+                    first: (0, 2),
+                    second: (1, 1),
+                    sample: None,
+                },
+            ],
+        }
+    }
+
+    /// Write the reports of clusters and read the report file.
+    fn report(name: &str, clusters: &[ClusterReport]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("forgers-test-{}-{}", std::process::id(), name));
+        let mut report = Report::create(&path).unwrap();
+        for cluster in clusters {
+            report.write(cluster).unwrap();
+        }
+        report.finish().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("x:1:A:C"), "\"x:1:A:C\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("a\nb\tc\r"), "\"a\\nb\\tc\\u000d\"");
+        assert_eq!(json_string("\u{1}é"), "\"\\u0001é\"");
+    }
+
+    #[test]
+    fn test_report_tsv() {
+        let text = report("report.tsv", &[cluster(1)]);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                TSV_HEADER,
+                "1\tx\t10\t11\t0\tx:10:A:C,G\t3\tC\tselected\twon conflicts\t1.1@s1",
+                "1\tx\t10\t11\t0\tx:10:A:C,G\t3\tG\trejected\tbeaten by x:11:C:T\t1.1@.",
+                "1\tx\t10\t11\t1\tx:11:C:T\t.\tT\tselected\twon conflicts\t0.1@s1,0.2@.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_json() {
+        assert_eq!(report("empty.json", &[]), "[]\n");
+        let json = "{\"cluster\":1,\"chrom\":\"x\",\"start\":10,\"end\":11,\"members\":[\
+            {\"record\":\"x:10:A:C,G\",\"rank\":3,\"alleles\":[\
+            {\"alt\":\"C\",\"status\":\"selected\",\"reason\":\"won conflicts\"},\
+            {\"alt\":\"G\",\"status\":\"rejected\",\"reason\":\"beaten by x:11:C:T\"}]},\
+            {\"record\":\"x:11:C:T\",\"rank\":null,\"alleles\":[\
+            {\"alt\":\"T\",\"status\":\"selected\",\"reason\":\"won conflicts\"}]}],\
+            \"conflicts\":[{\"first\":[0,1],\"second\":[1,1],\"sample\":\"s1\"},\
+            {\"first\":[0,2],\"second\":[1,1],\"sample\":null}]}";
+        assert_eq!(
+            report("one.json", &[cluster(1)]),
+            format!("[\n{}\n]\n", json)
+        );
+        let text = report("many.json", &[cluster(1), cluster(2), cluster(3)]);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!((lines[0], lines[4]), ("[", "]"));
+        assert_eq!(lines[1], format!("{},", json));
+        assert_eq!(
+            lines[2],
+            format!("{},", json.replace("\"cluster\":1", "\"cluster\":2"))
+        );
+        assert_eq!(lines[3], json.replace("\"cluster\":1", "\"cluster\":3"));
+    }
+}
@@ -1,13 +1,123 @@
 use bitvec::prelude as bv;
-use log::{info, warn};
+use log::{error, info, warn};
 use std::cmp;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufWriter, Write};
 use std::iter::zip;
 use std::path::Path;
-use vcf::{VCFError, VCFReader, VCFRecord, VCFWriter};
+use vcf::{VCFError, VCFHeader, VCFRecord, VCFWriter};
 
+use crate::check::InputCheck;
+use crate::fasta::Fasta;
 use crate::forge;
-use crate::vcf_util::{parse_genotype, unwrap_genotype, Genotype};
+use crate::merge::{merge_cluster, MergeStats};
+use crate::option::{ConflictAction, MaskAllele, MissingPolicy, ResolveOpt, UnphasedPolicy};
+use crate::report::{AlleleReport, ClusterReport, Conflict, MemberReport, Report, Status};
+use crate::samples::subset_genotypes;
+use crate::screen::{Screen, Screening};
+use crate::vcf_util::{
+    extend_header, info_header_line, parse_genotype, phase_set, replace_alleles, trim_alt_alleles,
+    DynWriter, Genotype, RecordRead,
+};
+
+/// Maximum number of alleles in a conflict component solved exactly
+pub const MAX_EXACT_LIMIT: usize = 64;
+
+/// Number of alleles in a conflict component above which solving it exactly
+/// may take exponential time in practice
+pub const MAX_EXACT_WARN: usize = 32;
+
+/// INFO key naming the record that beat a rejected one
+const BEATEN_BY_KEY: &str = "BEATEN_BY";
+
+/// INFO key naming the record into which a rejected one is merged
+const MERGED_INTO_KEY: &str = "MERGED_INTO";
+
+/// INFO key listing the records merged into one
+const MERGED_KEY: &str = "MERGED";
+
+/// Decision on a record of an overlap cluster.
+#[derive(Debug, Clone, PartialEq)]
+enum Decision {
+    /// Selected to be written to the output
+    Selected,
+    /// Rejected in favour of the record at the given index of the cluster
+    BeatenBy(usize),
+    /// Selected with only the kept ALT alleles; the others are rejected in
+    /// favour of the record at the given index of the cluster
+    Trimmed { kept: Vec<bool>, beaten_by: usize },
+    /// Merged into the record with the given ID along with the others in the
+    /// cluster; the merged record is written in place of the first one
+    MergedInto {
+        id: String,
+        merged: Option<Box<VCFRecord>>,
+    },
+    /// Dropped for exceeding the allele length caps, failing the FILTER
+    /// check, or being forced out
+    Dropped,
+    /// Passed through unresolved with a FILTER tag for exceeding the allele
+    /// length caps
+    SoftFiltered,
+}
+
+/// Policies of coupling checks for samples whose haplotypes cannot be
+/// compared.
+struct CouplingRules {
+    missing: MissingPolicy,
+    unphased: UnphasedPolicy,
+    /// Samples counting as evidence of coupling; all if not given
+    samples: Option<Vec<bool>>,
+}
+
+/// Evidence of two alleles being coupled.
+enum Evidence {
+    /// The alleles are coupled in the sample at the given index
+    Sample(usize),
+    /// No sample is evidence either way; the alleles are assumed coupled
+    Assumed,
+}
+
+/// Statistics of coupling checks.
+#[derive(Debug, Default)]
+struct CouplingStats {
+    /// Number of genotype pairs with a missing genotype
+    missing: usize,
+    /// Number of genotype pairs with an unphased genotype
+    unphased: usize,
+    /// Number of phased genotype pairs in different phase sets checked by the
+    /// unphased policy
+    phase_set_fallbacks: usize,
+    /// Number of phased genotype pairs of different ploidies
+    ploidy_mismatches: usize,
+    /// Number of allele pairs assumed coupled as every sample is skipped
+    uninformative: usize,
+    /// Number of invalid genotypes taken as missing
+    invalid: usize,
+    /// Number of alleles masked on conflicting haplotypes
+    masked: usize,
+}
+
+/// Optional resources of resolving opened by the caller.
+#[derive(Default)]
+pub struct Resources {
+    /// Reference sequences; required for merging conflicting records
+    pub fasta: Option<Fasta>,
+    /// Report of the resolved clusters
+    pub report: Option<Report>,
+    /// Samples counting as evidence of coupling; all if not given
+    pub samples: Option<Vec<bool>>,
+}
+
+/// State of resolving shared by the clusters.
+struct State {
+    rules: CouplingRules,
+    coupling: CouplingStats,
+    merge: MergeStats,
+    resources: Resources,
+    /// Samples whose genotypes are written; all if not given
+    output_samples: Option<Vec<bool>>,
+    /// Number of clusters of more than one competing record so far
+    nof_clusters: usize,
+}
 
 struct PosRange {
     start: u64,
@@ -35,9 +145,38 @@ struct PosRange {
 /// in a sample (i.e. they are in coupling configuration in at least one
 /// sample). For exmaple, the last two records are not conflicting since there
 /// is no sample that have both alleles on the same haplotype.
-fn resolve_cluster(cluster: &[VCFRecord], ranks: &forge::RegSiteMap) -> Vec<usize> {
-    let mut processed = bv::bitvec![0; cluster.len()];
-    let mut selected = Vec::new();
+///
+/// Conflicts are decided per ALT allele: each ALT allele of a multi-allelic
+/// record competes on its own with the rank of the record, and the ALT alleles
+/// of one record never conflict with each other.
+///
+/// The alleles to keep are a maximum-weight independent set of the conflict
+/// graph, whose nodes are the ALT alleles and edges are the conflicts. The
+/// weight of an allele is derived from the rank of its record (see
+/// [`weight`]), so that a set of lower-ranked alleles may be kept over a
+/// higher-ranked one conflicting with all of them. Each connected component of
+/// the graph is solved exactly by branch and bound if it has at most
+/// `max_exact` alleles. Larger components are solved greedily: alleles are
+/// visited in the order of rank and kept if they conflict with no kept one.
+/// Ties are broken in favour of the better-ranked alleles.
+///
+/// Records forced to be included win over all others and are never rejected.
+/// When two forced records conflict, both are selected and the clash is
+/// reported. Records failing the FILTER check, if kept, are treated as
+/// unranked.
+///
+/// It returns, for each ALT allele of each record, the index of the record
+/// that beat it, if rejected; i.e. the best-ranked kept record conflicting
+/// with it. The conflicts found are returned along with it.
+fn resolve_cluster(
+    cluster: &[VCFRecord],
+    ranking: &forge::Ranking,
+    screenings: &[Screening],
+    max_exact: usize,
+    rules: &CouplingRules,
+    stats: &mut CouplingStats,
+) -> (Vec<Vec<Option<usize>>>, Vec<Conflict>) {
+    let ranks = &ranking.sites;
     for (idx, record) in cluster.iter().enumerate() {
         let rank = forge::forge_rank(record, ranks).unwrap_or(&usize::MAX);
         info!(
@@ -48,31 +187,379 @@ fn resolve_cluster(cluster: &[VCFRecord], ranks: &forge::RegSiteMap) -> Vec<usiz
             rank
         );
     }
+    // each ALT allele of each record as (record index, allele index)
+    let units: Vec<(usize, usize)> = cluster
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, record)| (1..=record.alternative.len()).map(move |a| (idx, a)))
+        .collect();
+    let keys: Vec<((bool, usize), usize)> = units
+        .iter()
+        .enumerate()
+        .map(|(i, (r, _))| (priority(&cluster[*r], screenings[*r], ranks), i))
+        .collect();
+    let samples = cluster[0].header().samples();
+    let mut neighbours: Vec<Vec<usize>> = vec![Vec::new(); units.len()];
+    let mut conflicts = Vec::new();
+    for (i, (rec1, allele1)) in units.iter().enumerate() {
+        for (j, (rec2, allele2)) in units.iter().enumerate().skip(i + 1) {
+            if rec1 == rec2 {
+                continue;
+            }
+            let first = &cluster[*rec1];
+            let second = &cluster[*rec2];
+            let evidence = conflict_evidence(first, *allele1, second, *allele2, rules, stats);
+            if let Some(evidence) = evidence {
+                neighbours[i].push(j);
+                neighbours[j].push(i);
+                conflicts.push(Conflict {
+                    first: units[i],
+                    second: units[j],
+                    sample: match evidence {
+                        Evidence::Sample(idx) => {
+                            Some(String::from_utf8_lossy(&samples[idx]).into_owned())
+                        }
+                        Evidence::Assumed => None,
+                    },
+                });
+            }
+        }
+    }
 
-    let mut idx = 0;
-    while idx < cluster.len() {
-        if !processed[idx] {
-            let record = &cluster[idx];
-            let mut hi_idx = idx;
-            let mut hi_rank = forge::forge_rank(record, ranks).unwrap_or(&usize::MAX);
-            for (offset, other) in cluster[idx + 1..].iter().enumerate() {
-                let cursor = idx + offset + 1;
-                if are_conflicting(record, other) {
-                    processed.set(cursor, true);
-                    let other_rank = forge::forge_rank(other, ranks).unwrap_or(&usize::MAX);
-                    if other_rank < hi_rank {
-                        hi_rank = other_rank;
-                        hi_idx = cursor;
+    let forced: Vec<bool> = units
+        .iter()
+        .map(|(r, _)| screenings[*r] == Screening::ForcedIn)
+        .collect();
+    let mut selected = bv::bitvec![0; units.len()];
+    for idx in (0..units.len()).filter(|i| forced[*i]) {
+        selected.set(idx, true);
+        for other in neighbours[idx].iter().filter(|j| **j > idx && forced[**j]) {
+            warn!(
+                "Forced records {} and {} clash; keeping both",
+                record_id(&cluster[units[idx].0]),
+                record_id(&cluster[units[*other].0])
+            );
+        }
+    }
+    // alleles not decided by forced ones
+    let mut free: Vec<bool> = (0..units.len())
+        .map(|i| !forced[i] && !neighbours[i].iter().any(|j| forced[*j]))
+        .collect();
+    for start in 0..units.len() {
+        if !free[start] {
+            continue;
+        }
+        // connected component of free alleles in the order of priority
+        free[start] = false;
+        let mut component = vec![start];
+        let mut cursor = 0;
+        while cursor < component.len() {
+            for next in neighbours[component[cursor]].iter() {
+                if free[*next] {
+                    free[*next] = false;
+                    component.push(*next);
+                }
+            }
+            cursor += 1;
+        }
+        component.sort_by_key(|i| keys[*i]);
+        if component.len() <= max_exact {
+            let adjacency: Vec<u64> = component
+                .iter()
+                .map(|i| {
+                    component
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, j)| neighbours[*i].contains(j))
+                        .fold(0, |mask, (bit, _)| mask | (1 << bit))
+                })
+                .collect();
+            let weights: Vec<u64> = component
+                .iter()
+                .map(|i| weight(keys[*i].0 .1, ranking.total))
+                .collect();
+            let chosen = max_weight_set(&adjacency, &weights);
+            for (bit, idx) in component.iter().enumerate() {
+                selected.set(*idx, chosen & (1 << bit) != 0);
+            }
+        } else {
+            info!(
+                "Resolving {} conflicting alleles greedily by rank",
+                component.len()
+            );
+            for idx in component.iter() {
+                let kept = !neighbours[*idx].iter().any(|j| selected[*j]);
+                selected.set(*idx, kept);
+            }
+        }
+    }
+    info!(
+        "Selected {:?}",
+        selected.iter_ones().map(|i| units[i]).collect::<Vec<_>>()
+    );
+    let mut alleles: Vec<Vec<Option<usize>>> = cluster.iter().map(|_| Vec::new()).collect();
+    for (idx, (rec_idx, _)) in units.iter().enumerate() {
+        let winner = match selected[idx] {
+            true => None,
+            false => neighbours[idx]
+                .iter()
+                .filter(|j| selected[**j])
+                .min_by_key(|j| keys[**j])
+                .map(|j| units[*j].0),
+        };
+        alleles[*rec_idx].push(winner);
+    }
+    (alleles, conflicts)
+}
+
+/// Alleles of a sample in a record subject to masking.
+struct Haplotypes {
+    phased: bool,
+    alleles: Vec<usize>,
+    masked: Vec<bool>,
+}
+
+impl Haplotypes {
+    fn new(genotype: Genotype) -> Option<Self> {
+        let (phased, alleles) = match genotype {
+            Genotype::Phased(v) => (true, v),
+            Genotype::Unphased(v) => (false, v),
+            Genotype::Missing => return None,
+        };
+        let masked = vec![false; alleles.len()];
+        Some(Haplotypes {
+            phased,
+            alleles,
+            masked,
+        })
+    }
+
+    /// Get the genotype with the masked alleles as REF.
+    fn genotype(haps: Option<&Self>) -> Genotype {
+        let haps = match haps {
+            Some(haps) => haps,
+            None => return Genotype::Missing,
+        };
+        let alleles = zip(&haps.alleles, &haps.masked)
+            .map(|(allele, masked)| if *masked { 0 } else { *allele })
+            .collect();
+        match haps.phased {
+            true => Genotype::Phased(alleles),
+            false => Genotype::Unphased(alleles),
+        }
+    }
+}
+
+/// Mask the alleles of lower-ranked records on the haplotypes where they
+/// conflict with higher-ranked ones, instead of dropping the records.
+///
+/// Records are visited in the order of priority (see [`priority`]). Each pair
+/// of ALT alleles of two records overlapping in [`variant_ref_range`] is
+/// checked for coupling by the coupling policies (see [`coupling_evidence`])
+/// after the masks so far. If they conflict, the lower-ranked allele is masked
+/// in every sample in which the two are coupled by the same policies: on the
+/// haplotypes carrying both if they are compared haplotype by haplotype, or
+/// one copy otherwise. Missing genotypes are never masked.
+///
+/// It returns the conflicts found.
+fn mask_cluster(
+    cluster: &mut [VCFRecord],
+    ranking: &forge::Ranking,
+    screenings: &[Screening],
+    mask: MaskAllele,
+    rules: &CouplingRules,
+    stats: &mut CouplingStats,
+) -> Vec<Conflict> {
+    let samples = cluster[0].header().samples().to_vec();
+    let order = priority_order(cluster, screenings, &ranking.sites);
+    let mut haplotypes: Vec<Vec<Option<Haplotypes>>> = cluster
+        .iter()
+        .map(|record| {
+            samples
+                .iter()
+                .map(|sample| Haplotypes::new(sample_genotype(record, sample, &mut stats.invalid)))
+                .collect()
+        })
+        .collect();
+    let mut conflicts = Vec::new();
+    for (pos, hi) in order.iter().enumerate() {
+        for lo in order[pos + 1..].iter() {
+            let (hi_record, lo_record) = (&cluster[*hi], &cluster[*lo]);
+            if !is_range_overlapping(&variant_ref_range(hi_record), &variant_ref_range(lo_record)) {
+                continue;
+            }
+            let same_phase_sets: Vec<bool> = samples
+                .iter()
+                .map(|sample| is_same_phase_set(hi_record, lo_record, sample))
+                .collect();
+            for hi_allele in 1..=hi_record.alternative.len() {
+                for lo_allele in 1..=lo_record.alternative.len() {
+                    let genotypes = |sdx: usize| {
+                        (
+                            Haplotypes::genotype(haplotypes[*hi][sdx].as_ref()),
+                            Haplotypes::genotype(haplotypes[*lo][sdx].as_ref()),
+                            same_phase_sets[sdx],
+                        )
+                    };
+                    let evidence = match find_coupling(
+                        &samples, hi_allele, lo_allele, genotypes, rules, stats,
+                    ) {
+                        Some(evidence) => evidence,
+                        None => continue,
+                    };
+                    conflicts.push(Conflict {
+                        first: (*hi, hi_allele),
+                        second: (*lo, lo_allele),
+                        sample: match evidence {
+                            Evidence::Sample(idx) => {
+                                Some(String::from_utf8_lossy(&samples[idx]).into_owned())
+                            }
+                            Evidence::Assumed => None,
+                        },
+                    });
+                    for sdx in 0..samples.len() {
+                        let hi_gt = Haplotypes::genotype(haplotypes[*hi][sdx].as_ref());
+                        let lo_gt = Haplotypes::genotype(haplotypes[*lo][sdx].as_ref());
+                        let coupled = sample_coupling(
+                            &hi_gt,
+                            hi_allele,
+                            &lo_gt,
+                            lo_allele,
+                            same_phase_sets[sdx],
+                            rules,
+                        );
+                        let l = match (coupled, haplotypes[*lo][sdx].as_mut()) {
+                            ((check, Some(true)), Some(l)) => {
+                                if let (Check::Haplotypes, Genotype::Phased(h)) = (check, &hi_gt) {
+                                    for (hap, allele) in h.iter().enumerate() {
+                                        if *allele == hi_allele && l.alleles[hap] == lo_allele {
+                                            l.masked[hap] = true;
+                                        }
+                                    }
+                                    continue;
+                                }
+                                l
+                            }
+                            _ => continue,
+                        };
+                        if let Some(hap) = (0..l.alleles.len())
+                            .find(|hap| l.alleles[*hap] == lo_allele && !l.masked[*hap])
+                        {
+                            l.masked[hap] = true;
+                        }
                     }
                 }
             }
-            selected.push(hi_idx);
         }
-        idx += 1;
     }
-    selected.sort();
-    info!("Selected {:?}", selected);
-    selected
+    let allele: &[u8] = match mask {
+        MaskAllele::Ref => b"0",
+        MaskAllele::Missing => b".",
+    };
+    for (record, record_haps) in zip(cluster.iter_mut(), haplotypes) {
+        for (sample, haps) in zip(&samples, record_haps) {
+            let haps = match haps {
+                Some(haps) if haps.masked.iter().any(|m| *m) => haps,
+                _ => continue,
+            };
+            stats.masked += haps.masked.iter().filter(|m| **m).count();
+            if let Some(gt) = record
+                .genotype_mut(sample, b"GT")
+                .and_then(|v| v.first_mut())
+            {
+                *gt = replace_alleles(gt, &haps.masked, allele);
+            }
+        }
+    }
+    conflicts
+}
+
+/// Get the weight of an allele in resolving conflicts by its rank.
+///
+/// Ranked alleles weigh `2 * (total + 1 - rank)`, where `total` is the number
+/// of entries in the rank file, and unranked ones weigh 1; so that any ranked
+/// allele outweighs an unranked one.
+fn weight(rank: usize, total: usize) -> u64 {
+    match rank {
+        usize::MAX => 1,
+        rank => 2 * (total + 1 - cmp::min(rank, total)) as u64,
+    }
+}
+
+/// Find a maximum-weight independent set of a graph by branch and bound.
+///
+/// The graph is given by the adjacency bit masks of at most 64 nodes in the
+/// order of priority. Among the sets of equal weight, it returns the first one
+/// found by including the nodes in this order; i.e. favouring the nodes of
+/// higher priority. It returns the bit mask of the nodes in the set.
+fn max_weight_set(adjacency: &[u64], weights: &[u64]) -> u64 {
+    fn search(
+        candidates: u64,
+        chosen: u64,
+        weight: u64,
+        best: &mut (u64, u64),
+        adjacency: &[u64],
+        weights: &[u64],
+    ) {
+        if candidates == 0 {
+            if weight > best.0 {
+                *best = (weight, chosen);
+            }
+            return;
+        }
+        let bound: u64 = (0..weights.len())
+            .filter(|v| candidates & (1 << v) != 0)
+            .map(|v| weights[v])
+            .sum();
+        if weight + bound <= best.0 {
+            return;
+        }
+        let v = candidates.trailing_zeros() as usize;
+        let bit = 1 << v;
+        search(
+            candidates & !bit & !adjacency[v],
+            chosen | bit,
+            weight + weights[v],
+            best,
+            adjacency,
+            weights,
+        );
+        // excluding a node is only worth it if any of its neighbours can be
+        // included instead
+        if adjacency[v] & candidates != 0 {
+            search(candidates & !bit, chosen, weight, best, adjacency, weights);
+        }
+    }
+
+    let nodes = match adjacency.len() {
+        0 => return 0,
+        n => u64::MAX >> (MAX_EXACT_LIMIT - n),
+    };
+    let mut best = (0, 0);
+    search(nodes, 0, 0, &mut best, adjacency, weights);
+    best.1
+}
+
+/// Get the priority of a record in resolving conflicts; lower is better.
+///
+/// Forced records come first and the others are ordered by their rank.
+fn priority(record: &VCFRecord, screening: Screening, ranks: &forge::RegSiteMap) -> (bool, usize) {
+    let rank = match screening {
+        Screening::Unranked => usize::MAX,
+        _ => *forge::forge_rank(record, ranks).unwrap_or(&usize::MAX),
+    };
+    (screening != Screening::ForcedIn, rank)
+}
+
+/// Get the indices of the records of a cluster in the order of priority.
+fn priority_order(
+    cluster: &[VCFRecord],
+    screenings: &[Screening],
+    ranks: &forge::RegSiteMap,
+) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..cluster.len()).collect();
+    order.sort_by_key(|i| (priority(&cluster[*i], screenings[*i], ranks), *i));
+    order
 }
 
 /// Get site positional range of a record relative to the reference sequence.
@@ -100,7 +587,7 @@ fn site_ref_range(record: &VCFRecord) -> PosRange {
 ///
 /// **NOTE**: The range is inclusive.
 ///
-/// This range, together with [`are_coupled`], is used to determine conflicting
+/// This range, together with [`coupling_evidence`], is used to determine conflicting
 /// records in an overlapping cluster. For example, the following record has a
 /// "variant range" of (21, 23), since the first base is not affected by the
 /// variant:
@@ -118,93 +605,384 @@ fn variant_ref_range(record: &VCFRecord) -> PosRange {
     PosRange { start, end }
 }
 
-/// Check whether two variants are in coupling configuration in at least one
-/// sample in the cohort.
+/// Find a sample in the cohort in which two ALT alleles are in coupling
+/// configuration.
+///
+/// Phased genotypes of any ploidy are compared haplotype by haplotype. When
+/// the ploidies of a sample differ in the two records, e.g. haploid and
+/// diploid calls across a PAR boundary, the haplotypes cannot be matched; the
+/// alleles are then considered coupled in the sample if both are present.
 ///
-/// It requires phased VCF file. Otherwise, it reports any two alleles as
-/// coupled.
-fn are_coupled(record1: &VCFRecord, record2: &VCFRecord) -> bool {
+/// Phased genotypes are only compared if they are in the same phase set
+/// (FORMAT/PS); records without FORMAT/PS are in one phase set. Otherwise, as
+/// for unphased genotypes, the haplotypes cannot be compared and the unphased
+/// policy applies (see [`unphased_coupling`]). Samples with a missing genotype
+/// in either record are decided by the missing policy; invalid genotypes are
+/// taken as missing and counted in the statistics.
+///
+/// All selected samples are checked until one proves the coupling; the samples
+/// skipped by the policies are no evidence either way. If every selected
+/// sample is skipped, the alleles are assumed to be coupled.
+///
+/// # Arguments
+///
+/// * `record1` - First VCF record
+/// * `allele1` - Index of the ALT allele of the first record; starting from 1
+/// * `record2` - Second VCF record
+/// * `allele2` - Index of the ALT allele of the second record; starting from 1
+/// * `rules` - Coupling policies
+/// * `stats` - Statistics of coupling checks
+fn coupling_evidence(
+    record1: &VCFRecord,
+    allele1: usize,
+    record2: &VCFRecord,
+    allele2: usize,
+    rules: &CouplingRules,
+    stats: &mut CouplingStats,
+) -> Option<Evidence> {
     if record1.header() != record2.header() {
         panic!("Inconsistent VCF headers");
     }
 
-    for sample in record1.header().samples() {
-        let gt1 = unwrap_genotype(parse_genotype(record1.genotype(sample, b"GT")), sample);
-        let gt2 = unwrap_genotype(parse_genotype(record2.genotype(sample, b"GT")), sample);
-        match (gt1, gt2) {
-            (Genotype::Missing, Genotype::Missing) => {
-                warn!(
-                    "Missing genotype fields for boths records for sample '{}'",
-                    std::str::from_utf8(sample).unwrap()
-                );
-                warn!("  consider sites with missing genotypes coupled");
-                return true;
-            }
-            (gt, Genotype::Missing) | (Genotype::Missing, gt) => {
-                warn!(
-                    "Missing genotype field in at least one record for sample '{}'",
-                    std::str::from_utf8(sample).unwrap()
-                );
-                warn!("  checking heterozygosity of the other site");
-                return !is_ref_hom(&gt).unwrap();
+    let samples = record1.header().samples();
+    let mut invalid = 0;
+    let genotypes = |idx: usize| {
+        let sample = &samples[idx];
+        (
+            sample_genotype(record1, sample, &mut invalid),
+            sample_genotype(record2, sample, &mut invalid),
+            is_same_phase_set(record1, record2, sample),
+        )
+    };
+    let evidence = find_coupling(samples, allele1, allele2, genotypes, rules, stats);
+    stats.invalid += invalid;
+    evidence
+}
+
+/// Get the genotype of a sample in a record; an invalid one is counted in
+/// `invalid` and taken as missing.
+fn sample_genotype(record: &VCFRecord, sample: &[u8], invalid: &mut usize) -> Genotype {
+    parse_genotype(record.genotype(sample, b"GT")).unwrap_or_else(|_| {
+        *invalid += 1;
+        Genotype::Missing
+    })
+}
+
+/// Find a sample in which two ALT alleles are coupled by the coupling
+/// policies (see [`coupling_evidence`]).
+///
+/// # Arguments
+///
+/// * `samples` - Names of the samples
+/// * `allele1` - Index of the first ALT allele; starting from 1
+/// * `allele2` - Index of the second ALT allele; starting from 1
+/// * `genotypes` - Genotypes of a sample with the given index in the two
+///   records, and whether they are in the same phase set
+/// * `rules` - Coupling policies
+/// * `stats` - Statistics of coupling checks
+fn find_coupling<F>(
+    samples: &[Vec<u8>],
+    allele1: usize,
+    allele2: usize,
+    mut genotypes: F,
+    rules: &CouplingRules,
+    stats: &mut CouplingStats,
+) -> Option<Evidence>
+where
+    F: FnMut(usize) -> (Genotype, Genotype, bool),
+{
+    let (mut checked, mut skipped) = (0, 0);
+    for (idx, sample) in samples.iter().enumerate() {
+        if rules
+            .samples
+            .as_ref()
+            .is_some_and(|selected| !selected[idx])
+        {
+            continue;
+        }
+        checked += 1;
+        let (gt1, gt2, same_phase_set) = genotypes(idx);
+        let (check, coupled) = sample_coupling(&gt1, allele1, &gt2, allele2, same_phase_set, rules);
+        stats.count(check);
+        let coupled = match coupled {
+            Some(coupled) => coupled,
+            None => {
+                skipped += 1;
+                continue;
             }
-            (Genotype::Phased(v1), Genotype::Phased(v2)) => {
-                if zip(v1, v2).any(|x| x.0 && x.1) {
-                    info!(
-                        "Found two alleles in coupling state in sample '{}'",
-                        std::str::from_utf8(sample).unwrap()
-                    );
-                    return true;
+        };
+        if coupled {
+            info!(
+                "Found two alleles in coupling state in sample '{}'",
+                std::str::from_utf8(sample).unwrap()
+            );
+            return Some(Evidence::Sample(idx));
+        }
+    }
+    if skipped != 0 && skipped == checked {
+        stats.uninformative += 1;
+        return Some(Evidence::Assumed);
+    }
+    None
+}
+
+/// Way of checking two genotypes of a sample for coupling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    /// Either genotype is missing; decided by the missing policy
+    Missing,
+    /// Phased genotypes in different phase sets; decided by the unphased
+    /// policy
+    PhaseSetFallback,
+    /// Phased genotypes of different ploidies; coupled if both are present
+    PloidyMismatch,
+    /// Phased genotypes compared haplotype by haplotype
+    Haplotypes,
+    /// Unphased genotypes; decided by the unphased policy
+    Unphased,
+}
+
+impl CouplingStats {
+    /// Count a coupling check of a genotype pair.
+    fn count(&mut self, check: Check) {
+        match check {
+            Check::Missing => self.missing += 1,
+            Check::PhaseSetFallback => self.phase_set_fallbacks += 1,
+            Check::PloidyMismatch => self.ploidy_mismatches += 1,
+            Check::Haplotypes => {}
+            Check::Unphased => self.unphased += 1,
+        }
+    }
+}
+
+/// Decide whether two ALT alleles are coupled in a sample by the coupling
+/// policies (see [`coupling_evidence`]).
+///
+/// It returns the way of the check and whether the alleles are coupled, or
+/// `None` if the sample is skipped.
+fn sample_coupling(
+    gt1: &Genotype,
+    allele1: usize,
+    gt2: &Genotype,
+    allele2: usize,
+    same_phase_set: bool,
+    rules: &CouplingRules,
+) -> (Check, Option<bool>) {
+    match (gt1, gt2) {
+        (Genotype::Missing, _) | (_, Genotype::Missing) => {
+            let coupled = match rules.missing {
+                // a missing genotype is assumed to carry the allele
+                MissingPolicy::Coupled => {
+                    Some(gt1.carries(allele1) != Some(false) && gt2.carries(allele2) != Some(false))
                 }
-            }
-            (gt1, gt2) => {
-                warn!(
-                    "Unphased genotypes in at least one record for sample '{}'",
-                    std::str::from_utf8(sample).unwrap()
-                );
-                warn!("  checking heterozygosity of both sites");
-                return !is_ref_hom(&gt1).unwrap() && !is_ref_hom(&gt2).unwrap();
-            }
+                MissingPolicy::Uncoupled => Some(false),
+                MissingPolicy::Skip => None,
+            };
+            (Check::Missing, coupled)
         }
+        (Genotype::Phased(v1), Genotype::Phased(v2)) if !same_phase_set => (
+            Check::PhaseSetFallback,
+            unphased_coupling(v1, allele1, v2, allele2, rules.unphased),
+        ),
+        (Genotype::Phased(v1), Genotype::Phased(v2)) if v1.len() != v2.len() => (
+            Check::PloidyMismatch,
+            Some(v1.contains(&allele1) && v2.contains(&allele2)),
+        ),
+        (Genotype::Phased(v1), Genotype::Phased(v2)) => (
+            Check::Haplotypes,
+            Some(zip(v1, v2).any(|x| *x.0 == allele1 && *x.1 == allele2)),
+        ),
+        (
+            Genotype::Phased(v1) | Genotype::Unphased(v1),
+            Genotype::Phased(v2) | Genotype::Unphased(v2),
+        ) => (
+            Check::Unphased,
+            unphased_coupling(v1, allele1, v2, allele2, rules.unphased),
+        ),
     }
-    false
 }
 
-/// Check whether the genotype is homozygous for reference allele.
-fn is_ref_hom(genotype: &Genotype) -> Option<bool> {
-    match genotype {
-        Genotype::Phased(v) | Genotype::Unphased(v) => Some(v.iter().all(|x| !x)),
-        Genotype::Missing => None,
+/// Decide whether two alleles are coupled in a sample whose haplotypes cannot
+/// be compared, by the unphased policy.
+///
+/// By the het rule, the alleles are coupled only if the allele counts imply
+/// it; i.e. they are more than the ploidy, e.g. one allele is homozygous.
+/// Otherwise, as for different ploidies, both alleles must be present.
+///
+/// It returns `None` if the sample is skipped.
+fn unphased_coupling(
+    alleles1: &[usize],
+    allele1: usize,
+    alleles2: &[usize],
+    allele2: usize,
+    policy: UnphasedPolicy,
+) -> Option<bool> {
+    let count1 = alleles1.iter().filter(|a| **a == allele1).count();
+    let count2 = alleles2.iter().filter(|a| **a == allele2).count();
+    let present = count1 != 0 && count2 != 0;
+    match policy {
+        UnphasedPolicy::Coupled => Some(present),
+        UnphasedPolicy::HetRule if alleles1.len() == alleles2.len() => {
+            Some(present && count1 + count2 > alleles1.len())
+        }
+        UnphasedPolicy::HetRule => Some(present),
+        UnphasedPolicy::Skip => None,
     }
 }
 
-/// Check whether two variants are conflicting.
+/// Check whether the genotypes of a sample in two records are in the same
+/// phase set; a missing phase set is the same as any other missing one.
+fn is_same_phase_set(record1: &VCFRecord, record2: &VCFRecord, sample: &[u8]) -> bool {
+    phase_set(record1, sample) == phase_set(record2, sample)
+}
+
+/// Find the evidence that two ALT alleles of two variants are conflicting.
 ///
-/// This means they are overlapping in [`variant_ref_range`] and coupled.
-fn are_conflicting(first: &VCFRecord, second: &VCFRecord) -> bool {
+/// This means they are overlapping in [`variant_ref_range`] and coupled (see
+/// [`coupling_evidence`]).
+fn conflict_evidence(
+    first: &VCFRecord,
+    allele1: usize,
+    second: &VCFRecord,
+    allele2: usize,
+    rules: &CouplingRules,
+    stats: &mut CouplingStats,
+) -> Option<Evidence> {
     let first_range = variant_ref_range(first);
     let second_range = variant_ref_range(second);
-    is_range_overlapping(&first_range, &second_range) && are_coupled(first, second)
+    if !is_range_overlapping(&first_range, &second_range) {
+        return None;
+    }
+    coupling_evidence(first, allele1, second, allele2, rules, stats)
+}
+
+/// Identify a record by its CHROM, POS, REF, and ALT fields.
+fn record_id(record: &VCFRecord) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        String::from_utf8_lossy(&record.chromosome),
+        record.position,
+        String::from_utf8_lossy(&record.reference),
+        String::from_utf8_lossy(&record.alternative.join(&b","[..]))
+    )
+}
+
+/// Get the output VCF header with the definitions of the added INFO fields.
+pub fn output_header(header: &VCFHeader, opts: &ResolveOpt) -> VCFHeader {
+    let mut lines = Vec::new();
+    if opts.conflict_action == ConflictAction::Merge {
+        lines.push(info_header_line(
+            MERGED_KEY,
+            ".",
+            "String",
+            "Records merged into this one (CHROM:POS:REF:ALT)",
+        ));
+    }
+    extend_header(header, lines)
+}
+
+/// Get the VCF header of rejected records stream.
+pub fn rejected_header(header: &VCFHeader, opts: &ResolveOpt) -> VCFHeader {
+    let mut lines = vec![info_header_line(
+        BEATEN_BY_KEY,
+        "1",
+        "String",
+        "Record that beat this one in resolving overlapping variants (CHROM:POS:REF:ALT)",
+    )];
+    if opts.conflict_action == ConflictAction::Merge {
+        lines.push(info_header_line(
+            MERGED_INTO_KEY,
+            "1",
+            "String",
+            "Record into which this one is merged with conflicting ones (CHROM:POS:REF:ALT)",
+        ));
+    }
+    extend_header(header, lines)
 }
 
-/// Write selected records from a cluster to the output stream.
+/// Write resolved records from a cluster to the output streams.
 ///
-/// **NOTE**: The selected indices must be sorted in order to preserve the order
-/// of records in the original VCF file.
-fn write_selected<W>(
+/// Selected records are written to the output stream and the rejected ones to
+/// the rejected stream, if any, annotated by the record that beat them. The
+/// rejected ALT alleles of trimmed records are written to the rejected stream
+/// likewise, and so are the records merged into one. The order of records in
+/// the original VCF file is preserved in both streams. Only the genotypes of
+/// the given samples are written, if any.
+fn write_cluster<W>(
     vcf_writer: &mut VCFWriter<BufWriter<W>>,
+    rejected: &mut Option<DynWriter>,
     cluster: &[VCFRecord],
-    selected: &Vec<usize>,
+    decisions: &[Decision],
+    screen: &Screen,
+    samples: Option<&[bool]>,
 ) -> Result<(), VCFError>
 where
     W: Write,
 {
-    for idx in selected {
-        vcf_writer.write_record(&cluster[*idx])?;
+    for (record, decision) in zip(cluster, decisions) {
+        match (decision, rejected.as_mut()) {
+            (Decision::Selected, _) => {
+                write_record(vcf_writer, record, samples)?;
+            }
+            (Decision::SoftFiltered, _) => {
+                let mut record = record.clone();
+                screen.soft_filter(&mut record);
+                write_record(vcf_writer, &record, samples)?;
+            }
+            (Decision::BeatenBy(winner), Some(rejected_writer)) => {
+                let mut record = record.clone();
+                let value = record_id(&cluster[*winner]).into_bytes();
+                record.insert_info(BEATEN_BY_KEY.as_bytes(), vec![value]);
+                write_record(rejected_writer, &record, samples)?;
+            }
+            (Decision::Trimmed { kept, beaten_by }, rejected_writer) => {
+                write_record(vcf_writer, &trim_alt_alleles(record, kept), samples)?;
+                if let Some(rejected_writer) = rejected_writer {
+                    let lost: Vec<bool> = kept.iter().map(|k| !k).collect();
+                    let mut record = trim_alt_alleles(record, &lost);
+                    let value = record_id(&cluster[*beaten_by]).into_bytes();
+                    record.insert_info(BEATEN_BY_KEY.as_bytes(), vec![value]);
+                    write_record(rejected_writer, &record, samples)?;
+                }
+            }
+            (Decision::MergedInto { id, merged }, rejected_writer) => {
+                if let Some(merged) = merged {
+                    write_record(vcf_writer, merged, samples)?;
+                }
+                if let Some(rejected_writer) = rejected_writer {
+                    let mut record = record.clone();
+                    record.insert_info(MERGED_INTO_KEY.as_bytes(), vec![id.clone().into_bytes()]);
+                    write_record(rejected_writer, &record, samples)?;
+                }
+            }
+            (Decision::Dropped, Some(rejected_writer)) => {
+                write_record(rejected_writer, record, samples)?;
+            }
+            (_, None) => {}
+        }
     }
     Ok(())
 }
 
+/// Write a record with only the genotypes of the given samples, if any.
+fn write_record<W>(
+    vcf_writer: &mut VCFWriter<BufWriter<W>>,
+    record: &VCFRecord,
+    samples: Option<&[bool]>,
+) -> Result<(), VCFError>
+where
+    W: Write,
+{
+    match samples {
+        Some(selected) => {
+            let mut record = record.clone();
+            subset_genotypes(&mut record, selected);
+            vcf_writer.write_record(&record)
+        }
+        None => vcf_writer.write_record(record),
+    }
+}
+
 /// Check whether two positional ranges are overlapping.
 fn is_range_overlapping(r1: &PosRange, r2: &PosRange) -> bool {
     let mut left = &r1;
@@ -229,61 +1007,564 @@ fn merge_range(r1: &PosRange, r2: &PosRange) -> PosRange {
 /// * `vcf_reader` - VCF input stream
 /// * `vcf_writer` - VCF output stream
 /// * `ranks_path` - FORGe ranking file path
+/// * `opts` - Resolve options
+/// * `screen` - Screen of records by forced lists, FILTER column, and allele
+///   length caps; forced records never lose a conflict to unforced ones, and
+///   oversized ones are dropped or passed through with a FILTER tag without
+///   taking part in clusters
+/// * `rejected` - Optional VCF output stream for the records resolved out
+/// * `resources` - Reference sequences and report of the resolved clusters
 ///
 /// **NOTE**: The input VCF file must be sorted by CHROM and POS and variants
-/// should be normalised.
-pub fn resolve<T, W, R>(
+/// should be normalised. Records violating either, see [`InputCheck`], stop
+/// resolving unless the options are lenient, in which case they are reported
+/// and resolved as they are.
+pub fn resolve<T, W, S>(
     mut vcf_writer: VCFWriter<BufWriter<W>>,
-    mut vcf_reader: VCFReader<BufReader<R>>,
+    mut vcf_reader: S,
     ranks_path: &T,
+    opts: &ResolveOpt,
+    screen: &Screen,
+    mut rejected: Option<DynWriter>,
+    mut resources: Resources,
 ) -> Result<(), VCFError>
 where
     T: AsRef<Path>,
     W: Write,
-    R: Read,
+    S: RecordRead,
 {
-    let ranks = forge::load_rank(ranks_path, 1.0);
-    let mut cur_record = VCFRecord::new(vcf_reader.header().clone());
-    let mut pre_record = VCFRecord::new(vcf_reader.header().clone());
-    let pre_fetched = vcf_reader.next_record(&mut pre_record)?;
-    if pre_fetched {
-        let mut pre_range = site_ref_range(&pre_record);
-        let mut cluster = Vec::new();
-        loop {
-            let fetched = vcf_reader.next_record(&mut cur_record)?;
-            if fetched {
-                let mut cur_range = site_ref_range(&cur_record);
-                let p_chrom = &pre_record.chromosome;
-                let c_chrom = &cur_record.chromosome;
-                if p_chrom == c_chrom {
-                    if is_range_overlapping(&pre_range, &cur_range) {
-                        if cluster.is_empty() {
-                            cluster.push(pre_record.clone());
-                        }
-                        cluster.push(cur_record.clone());
-                        pre_range = merge_range(&pre_range, &cur_range);
-                        std::mem::swap(&mut pre_record, &mut cur_record);
-                        continue;
-                    }
+    let ranking = forge::load_rank(ranks_path, &[forge::Top::Fraction(1.0)]);
+    let mut record = VCFRecord::new(vcf_reader.header().clone());
+    let mut cluster: Vec<VCFRecord> = Vec::new();
+    let mut cluster_range = PosRange { start: 0, end: 0 };
+    let mut state = State {
+        output_samples: resources.samples.clone().filter(|_| opts.subset_output),
+        rules: CouplingRules {
+            missing: opts.missing,
+            unphased: opts.unphased,
+            samples: resources.samples.take(),
+        },
+        coupling: CouplingStats::default(),
+        merge: MergeStats::default(),
+        resources,
+        nof_clusters: 0,
+    };
+    let mut input_check = InputCheck::default();
+    let mut nof_issues = 0;
+    while vcf_reader.next_record(&mut record)? {
+        if let Some(issue) = input_check.check(&record, state.resources.fasta.as_mut()) {
+            let position = format!(
+                "{}:{}",
+                String::from_utf8_lossy(&record.chromosome),
+                record.position
+            );
+            if !opts.lenient {
+                error!("Invalid input record at {}: {}", position, issue);
+                error!("  the input must be sorted and normalised; see `--lenient`");
+                std::process::exit(1);
+            }
+            warn!("Invalid input record at {}: {}", position, issue);
+            nof_issues += 1;
+        }
+        let range = site_ref_range(&record);
+        // oversized records neither extend nor start an overlap cluster, but
+        // stay in it within its reach to keep the input order
+        let competing = screen.check(&record).is_competing();
+        if let Some(last) = cluster.last() {
+            let joins = match competing {
+                true => is_range_overlapping(&cluster_range, &range),
+                false => range.start <= cluster_range.end,
+            };
+            if last.chromosome == record.chromosome && joins {
+                if competing {
+                    cluster_range = merge_range(&cluster_range, &range);
                 }
-                if !cluster.is_empty() {
-                    info!(
-                        "Found a cluster of overlapping sites of size {}",
-                        cluster.len()
-                    );
-                    let selected = resolve_cluster(&cluster, &ranks);
-                    write_selected(&mut vcf_writer, &cluster, &selected)?;
-                    cluster.clear();
+                cluster.push(record.clone());
+                continue;
+            }
+            flush_cluster(
+                &mut vcf_writer,
+                &mut rejected,
+                &cluster,
+                &ranking,
+                opts,
+                screen,
+                &mut state,
+            )?;
+            cluster.clear();
+        }
+        cluster_range = if competing {
+            range
+        } else {
+            PosRange { start: 0, end: 0 }
+        };
+        cluster.push(record.clone());
+    }
+    flush_cluster(
+        &mut vcf_writer,
+        &mut rejected,
+        &cluster,
+        &ranking,
+        opts,
+        screen,
+        &mut state,
+    )?;
+    if let Some(report) = state.resources.report {
+        report.finish()?;
+    }
+    if nof_issues != 0 {
+        warn!(
+            "Resolved {} unsorted or non-normalised records as they are",
+            nof_issues
+        );
+    }
+    let stats = &state.coupling;
+    info!(
+        "Checked {} genotype pairs with missing genotypes by the '{:?}' policy",
+        stats.missing, opts.missing
+    );
+    info!(
+        "Checked {} genotype pairs with unphased genotypes and {} in different phase sets by the '{:?}' policy",
+        stats.unphased, stats.phase_set_fallbacks, opts.unphased
+    );
+    if stats.ploidy_mismatches != 0 {
+        warn!(
+            "Checked {} phased genotype pairs of different ploidies by presence of both alleles",
+            stats.ploidy_mismatches
+        );
+    }
+    if stats.uninformative != 0 {
+        warn!(
+            "Assumed {} allele pairs coupled as no sample is evidence either way",
+            stats.uninformative
+        );
+    }
+    if stats.invalid != 0 {
+        warn!(
+            "Took {} invalid genotypes as missing in coupling checks",
+            stats.invalid
+        );
+    }
+    match opts.conflict_action {
+        ConflictAction::Drop => {}
+        ConflictAction::Mask => info!("Masked {} alleles on conflicting haplotypes", stats.masked),
+        ConflictAction::Merge => {
+            let stats = &state.merge;
+            info!("Merged {} clusters of conflicting records", stats.merged);
+            info!(
+                "  leaving {} sample genotypes undetermined and {} alleles out of haplotypes",
+                stats.undetermined, stats.skipped
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Resolve a cluster of overlapping sites, if there is more than one, and write
+/// it to the output streams.
+///
+/// Records exceeding the allele length caps do not take part in resolving.
+/// With the merge action, a cluster with any conflict is merged into one
+/// record; or resolved by dropping records if it cannot be merged. Clusters of
+/// more than one competing record are written to the report, if any.
+fn flush_cluster<W>(
+    vcf_writer: &mut VCFWriter<BufWriter<W>>,
+    rejected: &mut Option<DynWriter>,
+    cluster: &[VCFRecord],
+    ranking: &forge::Ranking,
+    opts: &ResolveOpt,
+    screen: &Screen,
+    state: &mut State,
+) -> Result<(), VCFError>
+where
+    W: Write,
+{
+    let stats = &mut state.coupling;
+    let screenings: Vec<Screening> = cluster.iter().map(|r| screen.check(r)).collect();
+    let mut decisions: Vec<Decision> = screenings
+        .iter()
+        .map(|screening| match screening {
+            Screening::Pass | Screening::ForcedIn | Screening::Unranked => Decision::Selected,
+            Screening::ForcedOut | Screening::Oversized | Screening::Failed => Decision::Dropped,
+            Screening::SoftFiltered => Decision::SoftFiltered,
+        })
+        .collect();
+    let competing: Vec<usize> = (0..cluster.len())
+        .filter(|i| decisions[*i] == Decision::Selected)
+        .collect();
+    if competing.len() <= 1 {
+        let samples = state.output_samples.as_deref();
+        return write_cluster(vcf_writer, rejected, cluster, &decisions, screen, samples);
+    }
+    info!(
+        "Found a cluster of overlapping sites of size {}",
+        competing.len()
+    );
+    let mut records: Vec<VCFRecord> = competing.iter().map(|i| cluster[*i].clone()).collect();
+    let competing_screenings: Vec<Screening> = competing.iter().map(|i| screenings[*i]).collect();
+    let mut masked_cluster = None;
+    let found = if opts.conflict_action == ConflictAction::Mask {
+        let found = mask_cluster(
+            &mut records,
+            ranking,
+            &competing_screenings,
+            opts.mask_allele,
+            &state.rules,
+            stats,
+        );
+        let mut masked = cluster.to_vec();
+        for (idx, record) in zip(&competing, records) {
+            masked[*idx] = record;
+        }
+        masked_cluster = Some(masked);
+        found
+    } else {
+        let (beaten_by, found) = resolve_cluster(
+            &records,
+            ranking,
+            &competing_screenings,
+            opts.max_exact,
+            &state.rules,
+            stats,
+        );
+        let conflicting = beaten_by.iter().flatten().any(|w| w.is_some());
+        let merged = opts.conflict_action == ConflictAction::Merge
+            && conflicting
+            && merge_decisions(&records, &competing, &competing_screenings, ranking, state)
+                .map(|merged| {
+                    for (idx, decision) in zip(&competing, merged) {
+                        decisions[*idx] = decision;
+                    }
+                })
+                .is_some();
+        if !merged {
+            for (idx, alleles) in zip(&competing, beaten_by) {
+                let kept: Vec<bool> = alleles.iter().map(|w| w.is_none()).collect();
+                let winner = match alleles.iter().flatten().next() {
+                    Some(winner) => competing[*winner],
+                    None => continue,
+                };
+                decisions[*idx] = if kept.iter().any(|k| *k) {
+                    Decision::Trimmed {
+                        kept,
+                        beaten_by: winner,
+                    }
+                } else {
+                    Decision::BeatenBy(winner)
+                };
+            }
+        }
+        found
+    };
+    let conflicts = member_conflicts(found, &competing);
+    let output = masked_cluster.as_deref().unwrap_or(cluster);
+    state.nof_clusters += 1;
+    if let Some(report) = state.resources.report.as_mut() {
+        report.write(&cluster_report(
+            state.nof_clusters,
+            cluster,
+            output,
+            &screenings,
+            &decisions,
+            conflicts,
+            &ranking.sites,
+        ))?;
+    }
+    let samples = state.output_samples.as_deref();
+    write_cluster(vcf_writer, rejected, output, &decisions, screen, samples)
+}
+
+/// Map the conflicts between the competing records of a cluster to the
+/// indices of the records in the cluster.
+fn member_conflicts(conflicts: Vec<Conflict>, competing: &[usize]) -> Vec<Conflict> {
+    conflicts
+        .into_iter()
+        .map(|c| Conflict {
+            first: (competing[c.first.0], c.first.1),
+            second: (competing[c.second.0], c.second.1),
+            sample: c.sample,
+        })
+        .collect()
+}
+
+/// Merge the competing records of a cluster into one record.
+///
+/// It returns the decisions on the competing records, or `None` if they
+/// cannot be merged.
+fn merge_decisions(
+    records: &[VCFRecord],
+    competing: &[usize],
+    screenings: &[Screening],
+    ranking: &forge::Ranking,
+    state: &mut State,
+) -> Option<Vec<Decision>> {
+    let order = priority_order(records, screenings, &ranking.sites);
+    let fasta = state
+        .resources
+        .fasta
+        .as_mut()
+        .expect("reference required for merging");
+    match merge_cluster(records, &order, fasta, &mut state.merge) {
+        Ok(mut merged) => {
+            let ids = records.iter().map(|r| record_id(r).into_bytes()).collect();
+            merged.insert_info(MERGED_KEY.as_bytes(), ids);
+            let id = record_id(&merged);
+            let mut merged = Some(Box::new(merged));
+            Some(
+                competing
+                    .iter()
+                    .map(|_| Decision::MergedInto {
+                        id: id.clone(),
+                        merged: merged.take(),
+                    })
+                    .collect(),
+            )
+        }
+        Err(msg) => {
+            warn!(
+                "Cannot merge the cluster at {}: {}; dropping conflicting records",
+                record_id(&records[0]),
+                msg
+            );
+            None
+        }
+    }
+}
+
+/// Build the report of a resolved cluster.
+///
+/// # Arguments
+///
+/// * `id` - Cluster ID
+/// * `cluster` - Records of the cluster as read
+/// * `output` - Records of the cluster as written; differs from `cluster` only
+///   in masked genotypes
+/// * `screenings` - Screening of each record
+/// * `decisions` - Decision on each record
+/// * `conflicts` - Conflicts found between the ALT alleles of the records
+/// * `ranks` - FORGe ranks
+fn cluster_report(
+    id: usize,
+    cluster: &[VCFRecord],
+    output: &[VCFRecord],
+    screenings: &[Screening],
+    decisions: &[Decision],
+    conflicts: Vec<Conflict>,
+    ranks: &forge::RegSiteMap,
+) -> ClusterReport {
+    let (start, end) = zip(cluster, screenings)
+        .filter(|(_, screening)| screening.is_competing())
+        .map(|(record, _)| site_ref_range(record))
+        .fold((u64::MAX, 0), |(start, end), range| {
+            (cmp::min(start, range.start), cmp::max(end, range.end))
+        });
+    let members = cluster
+        .iter()
+        .enumerate()
+        .map(|(idx, record)| {
+            let selected_reason = |allele: usize| {
+                let in_conflict = conflicts
+                    .iter()
+                    .any(|c| c.first == (idx, allele) || c.second == (idx, allele));
+                if screenings[idx] == Screening::ForcedIn {
+                    "forced in".to_string()
+                } else if output[idx] != *record {
+                    "masked on conflicting haplotypes".to_string()
+                } else if in_conflict {
+                    "won conflicts".to_string()
                 } else {
-                    vcf_writer.write_record(&pre_record)?;
+                    "no conflict".to_string()
                 }
-                std::mem::swap(&mut pre_range, &mut cur_range);
-                std::mem::swap(&mut pre_record, &mut cur_record);
-            } else {
-                vcf_writer.write_record(&pre_record)?;
-                break;
+            };
+            let beaten_reason =
+                |winner: usize| format!("beaten by {}", record_id(&cluster[winner]));
+            let alleles = (1..=record.alternative.len())
+                .map(|allele| {
+                    let (status, reason) = match &decisions[idx] {
+                        Decision::Selected => (Status::Selected, selected_reason(allele)),
+                        Decision::BeatenBy(winner) => (Status::Rejected, beaten_reason(*winner)),
+                        Decision::Trimmed { kept, beaten_by } => match kept[allele - 1] {
+                            true => (Status::Selected, selected_reason(allele)),
+                            false => (Status::Rejected, beaten_reason(*beaten_by)),
+                        },
+                        Decision::MergedInto { id, .. } => {
+                            (Status::Merged, format!("merged into {}", id))
+                        }
+                        Decision::Dropped => {
+                            let reason = match screenings[idx] {
+                                Screening::ForcedOut => "forced out",
+                                Screening::Oversized => "oversized",
+                                _ => "failed filters",
+                            };
+                            (Status::Rejected, reason.to_string())
+                        }
+                        Decision::SoftFiltered => (Status::Passed, "oversized".to_string()),
+                    };
+                    AlleleReport {
+                        alt: String::from_utf8_lossy(&record.alternative[allele - 1]).into_owned(),
+                        status,
+                        reason,
+                    }
+                })
+                .collect();
+            MemberReport {
+                record: record_id(record),
+                rank: match screenings[idx] {
+                    Screening::Unranked => None,
+                    _ => forge::forge_rank(record, ranks).copied(),
+                },
+                alleles,
+            }
+        })
+        .collect();
+    ClusterReport {
+        id,
+        chrom: String::from_utf8_lossy(&cluster[0].chromosome).into_owned(),
+        start,
+        end,
+        members,
+        conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::xorshift;
+
+    /// Find a maximum-weight independent set by trying all subsets in the
+    /// order favoured by `max_weight_set` on ties.
+    fn brute_force(adjacency: &[u64], weights: &[u64]) -> (u64, u64) {
+        let n = adjacency.len();
+        let mut best = (0, 0);
+        for set in 0..(1u64 << n) {
+            if (0..n).any(|v| set & (1 << v) != 0 && adjacency[v] & set != 0) {
+                continue;
+            }
+            let weight = (0..n)
+                .filter(|v| set & (1 << v) != 0)
+                .map(|v| weights[v])
+                .sum();
+            if weight > best.0 || (weight == best.0 && favours(set, best.1)) {
+                best = (weight, set);
             }
         }
+        best
+    }
+
+    /// Check whether `a` includes the first node where the two sets differ.
+    fn favours(a: u64, b: u64) -> bool {
+        let diff = a ^ b;
+        diff != 0 && a & (1 << diff.trailing_zeros()) != 0
+    }
+
+    fn set_weight(set: u64, weights: &[u64]) -> u64 {
+        (0..weights.len())
+            .filter(|v| set & (1 << v) != 0)
+            .map(|v| weights[v])
+            .sum()
+    }
+
+    fn mask(rules: &CouplingRules) -> (Vec<String>, Vec<Conflict>, CouplingStats) {
+        let (_, mut cluster) = crate::vcf_util::parse_vcf(
+            "##fileformat=VCFv4.2
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ts1\ts2\ts3\ts4
+x\t10\t.\tAC\tA\t.\t.\t.\tGT\t1|0\t0/1\t./.\t0|1
+x\t11\t.\tC\tT\t.\t.\t.\tGT\t1|0\t0/1\t0/1\t1|0
+",
+        );
+        let ranking = forge::Ranking {
+            sites: [(b"x".to_vec(), [(10, 1), (11, 2)].into())].into(),
+            scores: Default::default(),
+            total: 2,
+            order: vec![1, 2],
+        };
+        let mut stats = CouplingStats::default();
+        let screenings = [Screening::Pass; 2];
+        let conflicts = mask_cluster(
+            &mut cluster,
+            &ranking,
+            &screenings,
+            MaskAllele::Ref,
+            rules,
+            &mut stats,
+        );
+        let record = &cluster[1];
+        let genotypes = record
+            .header()
+            .samples()
+            .iter()
+            .map(|s| String::from_utf8(record.genotype(s, b"GT").unwrap()[0].clone()).unwrap())
+            .collect();
+        (genotypes, conflicts, stats)
+    }
+
+    #[test]
+    fn test_mask_cluster() {
+        let mut rules = CouplingRules {
+            missing: MissingPolicy::Coupled,
+            unphased: UnphasedPolicy::Coupled,
+            samples: None,
+        };
+        let (genotypes, conflicts, stats) = mask(&rules);
+        assert_eq!(genotypes, vec!["0|0", "0/0", "0/0", "1|0"]);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!((conflicts[0].first, conflicts[0].second), ((0, 1), (1, 1)));
+        assert_eq!(conflicts[0].sample.as_deref(), Some("s1"));
+        assert_eq!(stats.masked, 3);
+
+        rules.unphased = UnphasedPolicy::HetRule;
+        rules.missing = MissingPolicy::Uncoupled;
+        let (genotypes, _, stats) = mask(&rules);
+        assert_eq!(genotypes, vec!["0|0", "0/1", "0/1", "1|0"]);
+        assert_eq!(stats.masked, 1);
+
+        // no evidence in the selected samples
+        rules.samples = Some(vec![false, false, false, true]);
+        let (genotypes, conflicts, stats) = mask(&rules);
+        assert_eq!(genotypes, vec!["1|0", "0/1", "0/1", "1|0"]);
+        assert!(conflicts.is_empty());
+        assert_eq!(stats.masked, 0);
+    }
+
+    #[test]
+    fn test_max_weight_set_small() {
+        assert_eq!(max_weight_set(&[], &[]), 0);
+        assert_eq!(max_weight_set(&[0], &[1]), 0b1);
+        // a path 0 - 1 - 2: the middle node outweighs both ends
+        assert_eq!(max_weight_set(&[0b010, 0b101, 0b010], &[2, 5, 2]), 0b010);
+        assert_eq!(max_weight_set(&[0b010, 0b101, 0b010], &[3, 5, 3]), 0b101);
+        // ties favour the nodes of higher priority
+        assert_eq!(max_weight_set(&[0b10, 0b01], &[4, 4]), 0b01);
+        assert_eq!(max_weight_set(&[0b010, 0b101, 0b010], &[2, 4, 2]), 0b101);
+    }
+
+    #[test]
+    fn test_max_weight_set_brute_force() {
+        // deterministic pseudo-random graphs
+        let mut next = xorshift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let n = (next() % 12 + 1) as usize;
+            let density = next() % 4 + 1;
+            let mut adjacency = vec![0u64; n];
+            for u in 0..n {
+                for v in u + 1..n {
+                    if next() % 5 < density {
+                        adjacency[u] |= 1 << v;
+                        adjacency[v] |= 1 << u;
+                    }
+                }
+            }
+            let weights: Vec<u64> = (0..n).map(|_| next() % 6 + 1).collect();
+            let set = max_weight_set(&adjacency, &weights);
+            assert!((0..n).all(|v| set & (1 << v) == 0 || adjacency[v] & set == 0));
+            assert_eq!(
+                set_weight(set, &weights),
+                brute_force(&adjacency, &weights).0,
+                "adjacency {:?}, weights {:?}",
+                adjacency,
+                weights
+            );
+        }
     }
-    Ok(())
 }
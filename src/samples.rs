@@ -0,0 +1,140 @@
+use vcf::{VCFHeader, VCFRecord};
+
+/// Selection of samples by name.
+///
+/// As in bcftools, a leading `^` negates the selection; i.e. all samples but
+/// the named ones are selected.
+pub struct SampleSelection {
+    names: Vec<String>,
+    negated: bool,
+}
+
+impl SampleSelection {
+    /// Make a selection from sample names; the first one may start with `^`.
+    pub fn new(mut names: Vec<String>) -> Self {
+        let negated = match names.first_mut() {
+            Some(first) if first.starts_with('^') => {
+                first.remove(0);
+                true
+            }
+            _ => false,
+        };
+        names.retain(|name| !name.is_empty());
+        SampleSelection { names, negated }
+    }
+
+    /// Make a selection from sample names, negated if `negated` is set.
+    pub fn with_negation(names: Vec<String>, negated: bool) -> Self {
+        SampleSelection { names, negated }
+    }
+
+    /// Mark the selected samples among the samples of a VCF file.
+    ///
+    /// It fails if a named sample is not in the VCF file or no sample is
+    /// selected.
+    pub fn select(&self, samples: &[Vec<u8>]) -> Result<Vec<bool>, String> {
+        if let Some(name) = self
+            .names
+            .iter()
+            .find(|name| !samples.iter().any(|s| s == name.as_bytes()))
+        {
+            return Err(format!("sample '{}' not found in the input", name));
+        }
+        let selected: Vec<bool> = samples
+            .iter()
+            .map(|s| self.names.iter().any(|name| s == name.as_bytes()) != self.negated)
+            .collect();
+        if !selected.iter().any(|s| *s) {
+            return Err("no sample selected".to_string());
+        }
+        Ok(selected)
+    }
+}
+
+/// Get the VCF header with only the selected samples.
+pub fn subset_header(header: &VCFHeader, selected: &[bool]) -> VCFHeader {
+    let samples = header
+        .samples()
+        .iter()
+        .zip(selected)
+        .filter(|(_, s)| **s)
+        .map(|(sample, _)| sample.clone())
+        .collect();
+    VCFHeader::new(header.items().to_vec(), samples)
+}
+
+/// Keep only the genotypes of the selected samples in a record to be written.
+///
+/// **NOTE**: The genotypes of the record no longer match its header; so it is
+/// only fit for writing with the header made by [`subset_header`].
+pub fn subset_genotypes(record: &mut VCFRecord, selected: &[bool]) {
+    let mut selected = selected.iter();
+    record
+        .genotype
+        .retain(|_| selected.next().copied().unwrap_or(false));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::parse_vcf;
+    use vcf::VCFWriter;
+
+    fn select(names: &[&str]) -> Result<Vec<bool>, String> {
+        let names = names.iter().map(|n| n.to_string()).collect();
+        let samples = [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        SampleSelection::new(names).select(&samples)
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(select(&["a", "c"]), Ok(vec![true, false, true]));
+        assert_eq!(select(&["^a"]), Ok(vec![false, true, true]));
+        assert_eq!(select(&["^a", "b"]), Ok(vec![false, false, true]));
+        // a lone '^' excludes no sample
+        assert_eq!(select(&["^"]), Ok(vec![true, true, true]));
+        assert_eq!(
+            select(&["a", "d"]),
+            Err("sample 'd' not found in the input".to_string())
+        );
+        assert_eq!(
+            select(&["^d"]),
+            Err("sample 'd' not found in the input".to_string())
+        );
+        assert_eq!(
+            select(&["^a", "b", "c"]),
+            Err("no sample selected".to_string())
+        );
+        let none = SampleSelection::with_negation(Vec::new(), false);
+        assert!(none.select(&[b"a".to_vec()]).is_err());
+    }
+
+    #[test]
+    fn test_subset() {
+        let (header, mut records) = parse_vcf(
+            "##fileformat=VCFv4.2
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ta\tb\tc
+x\t1\t.\tA\tC\t.\t.\t.\tGT\t0|1\t1|1\t0|0
+",
+        );
+        let selected = select(&["^b"]).unwrap();
+        let subset = subset_header(&header, &selected);
+        assert_eq!(subset.samples(), &[b"a".to_vec(), b"c".to_vec()]);
+        subset_genotypes(&mut records[0], &selected);
+        let mut output = Vec::new();
+        {
+            let mut writer = VCFWriter::new(&mut output, &subset).unwrap();
+            writer.write_record(&records[0]).unwrap();
+        }
+        let text = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = text.lines().skip_while(|l| l.starts_with("##")).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ta\tc",
+                "x\t1\t.\tA\tC\t.\t.\t.\tGT\t0|1\t0|0",
+            ]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use vcf::VCFRecord;
+
+use crate::force::{ForceLists, Forced};
+use crate::length::{self, LengthCheck};
+use crate::option::LengthOpt;
+use crate::vcf_util::filter_values;
+
+/// Outcome of screening a record before ranking and resolving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screening {
+    /// The record is subject to ranking and resolving
+    Pass,
+    /// The record is forced to be included
+    ForcedIn,
+    /// The record is forced to be excluded
+    ForcedOut,
+    /// The record fails the FILTER check and should be dropped
+    Failed,
+    /// The record fails the FILTER check and should be treated as unranked
+    Unranked,
+    /// The record exceeds the allele length caps and should be dropped
+    Oversized,
+    /// The record exceeds the allele length caps and should be kept with a
+    /// FILTER tag
+    SoftFiltered,
+}
+
+impl Screening {
+    /// Check whether the record takes part in ranking and resolving.
+    pub fn is_competing(&self) -> bool {
+        matches!(
+            self,
+            Screening::Pass | Screening::ForcedIn | Screening::Unranked
+        )
+    }
+}
+
+/// Screen of records by forced lists, FILTER column, and allele length caps.
+///
+/// Forced lists take precedence over the other checks.
+pub struct Screen<'a> {
+    pub length: &'a LengthOpt,
+    pub force: ForceLists,
+    /// Allowed FILTER values; all records pass if empty
+    pub filters: Vec<Vec<u8>>,
+    /// Treat the records failing the FILTER check as unranked instead of
+    /// dropping them
+    pub unrank_failed: bool,
+}
+
+impl Screen<'_> {
+    /// Screen a record.
+    pub fn check(&self, record: &VCFRecord) -> Screening {
+        match self.force.check(record) {
+            Some(Forced::Include) => Screening::ForcedIn,
+            Some(Forced::Exclude) => Screening::ForcedOut,
+            None if !self.passes_filters(record) => {
+                if self.unrank_failed {
+                    Screening::Unranked
+                } else {
+                    Screening::Failed
+                }
+            }
+            None => match length::check(record, self.length) {
+                LengthCheck::Pass => Screening::Pass,
+                LengthCheck::Drop => Screening::Oversized,
+                LengthCheck::SoftFilter => Screening::SoftFiltered,
+            },
+        }
+    }
+
+    /// Check whether any FILTER value of a record is allowed; missing FILTER
+    /// is matched by '.'.
+    fn passes_filters(&self, record: &VCFRecord) -> bool {
+        if self.filters.is_empty() {
+            return true;
+        }
+        if record.filter.is_empty() {
+            return self.filters.iter().any(|f| f == b".");
+        }
+        filter_values(record).any(|f| self.filters.iter().any(|g| g == f))
+    }
+
+    /// Add the soft filter ID to the FILTER column of a record.
+    pub fn soft_filter(&self, record: &mut VCFRecord) {
+        length::soft_filter(record, self.length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::parse_vcf;
+
+    #[test]
+    fn test_passes_filters() {
+        let (_, records) = parse_vcf(
+            "##fileformat=VCFv4.2
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+x\t1\t.\tA\tC\t.\t.\t.
+x\t2\t.\tA\tC\t.\tPASS\t.
+x\t3\t.\tA\tC\t.\tq10\t.
+x\t4\t.\tA\tC\t.\ts50;q10\t.
+x\t5\t.\tA\tC\t.\tq10;s50\t.
+x\t6\t.\tA\tC\t.\ts50\t.
+",
+        );
+        let length = LengthOpt {
+            max_ref_len: None,
+            max_alt_len: None,
+            max_svlen: None,
+            length_filter: None,
+        };
+        let screen = Screen {
+            length: &length,
+            force: ForceLists::default(),
+            filters: vec![b"q10".to_vec(), b".".to_vec()],
+            unrank_failed: false,
+        };
+        let passes: Vec<bool> = records.iter().map(|r| screen.passes_filters(r)).collect();
+        assert_eq!(passes, vec![true, false, true, true, true, false]);
+    }
+}
@@ -0,0 +1,376 @@
+use log::{info, warn};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{remove_file, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use vcf::{VCFError, VCFHeader, VCFRecord};
+
+use crate::vcf_util::{RecordRead, RecordWrite};
+
+/// Number of temporary files created so far, used to make unique file names
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Make a unique temporary file path in `tmpdir`.
+fn temp_path(tmpdir: &Path, ext: &str) -> PathBuf {
+    let n = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    tmpdir.join(format!("forgers-{}-{}.{}", std::process::id(), n, ext))
+}
+
+/// Sort key of a record: its FORGe rank followed by its input order
+type SortKey = (usize, usize);
+
+/// External-memory sorter of VCF records by FORGe rank.
+///
+/// Records are buffered in memory up to a fixed capacity. When the buffer is
+/// full, it is sorted and spilled into a temporary file. The sorted runs are
+/// then merged while writing the records into the output stream. Records with
+/// the same rank retain their input order.
+pub struct RankSorter {
+    header: VCFHeader,
+    tmpdir: PathBuf,
+    capacity: usize,
+    buffer: Vec<(SortKey, VCFRecord)>,
+    spills: Vec<PathBuf>,
+    count: usize,
+}
+
+/// A sorted run spilled into a temporary file.
+struct SpillReader {
+    reader: BufReader<File>,
+    header: VCFHeader,
+    line: Vec<u8>,
+    line_num: u64,
+}
+
+impl SpillReader {
+    fn open(path: &Path, header: &VCFHeader) -> Result<Self, VCFError> {
+        Ok(SpillReader {
+            reader: BufReader::new(File::open(path)?),
+            header: header.clone(),
+            line: Vec::new(),
+            line_num: 0,
+        })
+    }
+
+    /// Read the next record of the run with its sort key.
+    fn next(&mut self) -> Result<Option<(SortKey, VCFRecord)>, VCFError> {
+        self.line.clear();
+        if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(None);
+        }
+        self.line_num += 1;
+        let mut fields = self.line.splitn(3, |c| *c == b'\t');
+        let mut key =
+            || -> Option<usize> { std::str::from_utf8(fields.next()?).ok()?.parse().ok() };
+        let (rank, order) = match (key(), key()) {
+            (Some(rank), Some(order)) => (rank, order),
+            _ => return Err(VCFError::RecordParseError(self.line_num)),
+        };
+        let rest = fields
+            .next()
+            .ok_or(VCFError::RecordParseError(self.line_num))?;
+        let record = VCFRecord::from_bytes(rest, self.line_num, self.header.clone())?;
+        Ok(Some(((rank, order), record)))
+    }
+}
+
+impl RankSorter {
+    /// Create a sorter spilling into `tmpdir` every `capacity` records.
+    pub fn new<T>(header: &VCFHeader, tmpdir: &T, capacity: usize) -> Self
+    where
+        T: AsRef<Path>,
+    {
+        RankSorter {
+            header: header.clone(),
+            tmpdir: tmpdir.as_ref().to_path_buf(),
+            capacity: std::cmp::max(capacity, 1),
+            buffer: Vec::new(),
+            spills: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Add a record with its FORGe rank.
+    pub fn push(&mut self, rank: usize, record: VCFRecord) -> Result<(), VCFError> {
+        self.buffer.push(((rank, self.count), record));
+        self.count += 1;
+        if self.buffer.len() >= self.capacity {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Sort the buffer and write it into a new temporary file.
+    fn spill(&mut self) -> Result<(), VCFError> {
+        self.buffer.sort_by_key(|(key, _)| *key);
+        let path = temp_path(&self.tmpdir, "spill");
+        info!(
+            "Spilling {} records into '{}'",
+            self.buffer.len(),
+            path.display()
+        );
+        let mut writer = BufWriter::new(File::create(&path)?);
+        self.spills.push(path);
+        for ((rank, order), record) in self.buffer.drain(..) {
+            write!(writer, "{}\t{}\t", rank, order)?;
+            record.write_record(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write all records into the output stream in rank order.
+    pub fn finish(mut self, vcf_writer: &mut dyn RecordWrite) -> Result<(), VCFError> {
+        if self.spills.is_empty() {
+            self.buffer.sort_by_key(|(key, _)| *key);
+            for (_, record) in self.buffer.drain(..) {
+                vcf_writer.write(&record)?;
+            }
+            return Ok(());
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        let mut runs = Vec::new();
+        let mut heads = Vec::new();
+        let mut heap = BinaryHeap::new();
+        for (idx, path) in self.spills.iter().enumerate() {
+            let mut run = SpillReader::open(path, &self.header)?;
+            match run.next()? {
+                Some((key, record)) => {
+                    heap.push(Reverse((key, idx)));
+                    heads.push(Some(record));
+                }
+                None => heads.push(None),
+            }
+            runs.push(run);
+        }
+        while let Some(Reverse((_, idx))) = heap.pop() {
+            if let Some(record) = heads[idx].take() {
+                vcf_writer.write(&record)?;
+            }
+            if let Some((key, record)) = runs[idx].next()? {
+                heap.push(Reverse((key, idx)));
+                heads[idx] = Some(record);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RankSorter {
+    /// Remove the temporary files.
+    fn drop(&mut self) {
+        for path in &self.spills {
+            if let Err(e) = remove_file(path) {
+                warn!("Cannot remove temporary file '{}': {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Temporary buffer of VCF records for reading the input twice.
+///
+/// Records are written into a temporary file as they are pushed, and read back
+/// in the same order after calling [`RecordBuffer::into_reader`]. The temporary
+/// file is removed when the buffer is dropped before that.
+pub struct RecordBuffer {
+    header: VCFHeader,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+impl RecordBuffer {
+    /// Create a buffer in `tmpdir`.
+    pub fn new<T>(header: &VCFHeader, tmpdir: &T) -> Result<Self, VCFError>
+    where
+        T: AsRef<Path>,
+    {
+        let path = temp_path(tmpdir.as_ref(), "buffer");
+        info!("Buffering input records into '{}'", path.display());
+        Ok(RecordBuffer {
+            header: header.clone(),
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            count: 0,
+        })
+    }
+
+    /// Add a record to the buffer.
+    pub fn push(&mut self, record: &VCFRecord) -> Result<(), VCFError> {
+        self.count += 1;
+        record.write_record(&mut self.writer)?;
+        Ok(())
+    }
+
+    /// Finish writing and get a reader of the buffered records.
+    pub fn into_reader(mut self) -> Result<BufferReader, VCFError> {
+        self.writer.flush()?;
+        info!("Buffered {} input records", self.count);
+        let reader = BufReader::new(File::open(&self.path)?);
+        // the reader removes the temporary file from now on
+        let path = std::mem::take(&mut self.path);
+        Ok(BufferReader {
+            reader,
+            header: self.header.clone(),
+            path,
+            line: Vec::new(),
+            line_num: 0,
+        })
+    }
+}
+
+impl Drop for RecordBuffer {
+    /// Remove the temporary file unless handed over to a reader.
+    fn drop(&mut self) {
+        if self.path.as_os_str().is_empty() {
+            return;
+        }
+        if let Err(e) = remove_file(&self.path) {
+            warn!(
+                "Cannot remove temporary file '{}': {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Reader of the records in a [`RecordBuffer`].
+///
+/// The temporary file is removed when the reader is dropped.
+pub struct BufferReader {
+    reader: BufReader<File>,
+    header: VCFHeader,
+    path: PathBuf,
+    line: Vec<u8>,
+    line_num: u64,
+}
+
+impl RecordRead for BufferReader {
+    fn header(&self) -> &VCFHeader {
+        &self.header
+    }
+
+    fn next_record(&mut self, record: &mut VCFRecord) -> Result<bool, VCFError> {
+        self.line.clear();
+        if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+        self.line_num += 1;
+        record.parse_bytes(&self.line, self.line_num)?;
+        Ok(true)
+    }
+}
+
+impl Drop for BufferReader {
+    /// Remove the temporary file.
+    fn drop(&mut self) {
+        if let Err(e) = remove_file(&self.path) {
+            warn!(
+                "Cannot remove temporary file '{}': {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcf_util::parse_vcf;
+
+    const VCF: &str = "##fileformat=VCFv4.2
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+x\t1\t.\tA\tC\t.\t.\t.
+x\t2\t.\tA\tC\t.\t.\t.
+x\t3\t.\tA\tC\t.\t.\t.
+x\t4\t.\tA\tC\t.\t.\t.
+x\t5\t.\tA\tC\t.\t.\t.
+";
+
+    /// Collector of the written records.
+    struct Positions(Vec<u64>);
+
+    impl RecordWrite for Positions {
+        fn write(&mut self, record: &VCFRecord) -> Result<(), VCFError> {
+            self.0.push(record.position);
+            Ok(())
+        }
+    }
+
+    fn records() -> (VCFHeader, Vec<VCFRecord>) {
+        parse_vcf(VCF)
+    }
+
+    fn sort(capacity: usize, ranks: &[usize]) -> (Vec<u64>, usize) {
+        let (header, records) = records();
+        let tmpdir = std::env::temp_dir();
+        let mut sorter = RankSorter::new(&header, &tmpdir, capacity);
+        for (rank, record) in ranks.iter().zip(records) {
+            sorter.push(*rank, record).unwrap();
+        }
+        let nof_spills = sorter.spills.len();
+        let mut positions = Positions(Vec::new());
+        sorter.finish(&mut positions).unwrap();
+        (positions.0, nof_spills)
+    }
+
+    #[test]
+    fn test_rank_sorter_in_memory() {
+        assert_eq!(sort(10, &[3, 1, 2, 1, 5]), (vec![2, 4, 3, 1, 5], 0));
+    }
+
+    #[test]
+    fn test_rank_sorter_spills() {
+        // runs of two records merged, ties in the input order across runs
+        assert_eq!(sort(2, &[3, 1, 2, 1, 5]), (vec![2, 4, 3, 1, 5], 2));
+        assert_eq!(sort(1, &[5, 4, 3, 2, 1]), (vec![5, 4, 3, 2, 1], 5));
+    }
+
+    #[test]
+    fn test_rank_sorter_removes_spills() {
+        let (header, records) = records();
+        let tmpdir = std::env::temp_dir();
+        let mut sorter = RankSorter::new(&header, &tmpdir, 1);
+        for record in records {
+            sorter.push(1, record).unwrap();
+        }
+        let spills = sorter.spills.clone();
+        assert!(spills.iter().all(|p| p.exists()));
+        drop(sorter);
+        assert!(spills.iter().all(|p| !p.exists()));
+    }
+
+    #[test]
+    fn test_record_buffer() {
+        let (header, records) = records();
+        let tmpdir = std::env::temp_dir();
+        let mut buffer = RecordBuffer::new(&header, &tmpdir).unwrap();
+        for record in records.iter() {
+            buffer.push(record).unwrap();
+        }
+        let path = buffer.path.clone();
+        let mut reader = buffer.into_reader().unwrap();
+        let mut record = VCFRecord::new(header.clone());
+        let mut positions = Vec::new();
+        while reader.next_record(&mut record).unwrap() {
+            positions.push(record.position);
+        }
+        assert_eq!(positions, vec![1, 2, 3, 4, 5]);
+        drop(reader);
+        assert!(!path.exists());
+
+        // dropped before reading
+        let buffer = RecordBuffer::new(&header, &tmpdir).unwrap();
+        let path = buffer.path.clone();
+        assert!(path.exists());
+        drop(buffer);
+        assert!(!path.exists());
+    }
+}
@@ -3,13 +3,16 @@ use flate2::write::GzEncoder;
 use flate2::Compression;
 use log::{error, warn};
 use std::fs::File;
-use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Stdin, Stdout, Write};
-use std::path::Path;
-use vcf::{VCFError, VCFHeader, VCFReader, VCFRecord, VCFWriter};
+use std::io::{sink, stdin, stdout, BufRead, BufReader, BufWriter, Read, Stdin, Stdout, Write};
+use std::path::{Path, PathBuf};
+use vcf::{Number, VCFError, VCFHeader, VCFHeaderLine, VCFReader, VCFRecord, VCFWriter};
 
 use crate::option::Opt;
 
-/// TODO: Write documentation of this module
+// TODO: Write documentation of this module
+
+/// Placeholder in output path templates replaced by each top value
+pub const TOP_PLACEHOLDER: &str = "{top}";
 
 /// Stream type.
 pub enum StreamType {
@@ -57,6 +60,26 @@ where
     }
 }
 
+/// Check whether the path is a template containing [`TOP_PLACEHOLDER`].
+pub fn is_template<T>(path: &T) -> bool
+where
+    T: AsRef<Path>,
+{
+    path.as_ref().to_string_lossy().contains(TOP_PLACEHOLDER)
+}
+
+/// Expand a path template by replacing [`TOP_PLACEHOLDER`] with `value`.
+pub fn expand_template<T>(path: &T, value: &str) -> PathBuf
+where
+    T: AsRef<Path>,
+{
+    PathBuf::from(
+        path.as_ref()
+            .to_string_lossy()
+            .replace(TOP_PLACEHOLDER, value),
+    )
+}
+
 pub fn path_or<T>(path: &T, stdio: &str) -> String
 where
     T: AsRef<Path>,
@@ -72,6 +95,99 @@ pub trait Process<W: Write, R: Read> {
     fn process(&mut self, writer: VCFWriter<BufWriter<W>>, reader: VCFReader<BufReader<R>>);
 }
 
+/// Source of VCF records.
+pub trait RecordRead {
+    fn header(&self) -> &VCFHeader;
+    fn next_record(&mut self, record: &mut VCFRecord) -> Result<bool, VCFError>;
+}
+
+impl<R: BufRead> RecordRead for VCFReader<R> {
+    fn header(&self) -> &VCFHeader {
+        VCFReader::header(self)
+    }
+
+    fn next_record(&mut self, record: &mut VCFRecord) -> Result<bool, VCFError> {
+        VCFReader::next_record(self, record)
+    }
+}
+
+/// Destination of VCF records.
+pub trait RecordWrite {
+    fn write(&mut self, record: &VCFRecord) -> Result<(), VCFError>;
+}
+
+impl<W: Write> RecordWrite for VCFWriter<W> {
+    fn write(&mut self, record: &VCFRecord) -> Result<(), VCFError> {
+        self.write_record(record)
+    }
+}
+
+/// A record passing through the filtering stages of an output.
+pub struct Staged {
+    /// Index of the record in the input
+    pub idx: usize,
+    /// FORGe rank of the record
+    pub rank: usize,
+    /// Variant class of the record; `None` for the records exempted from the
+    /// stages, e.g. forced ones
+    pub class: Option<VariantClass>,
+    pub record: VCFRecord,
+}
+
+/// Records leaving a filtering stage once their fate is final.
+#[derive(Default)]
+pub struct Emitted {
+    pub kept: Vec<Staged>,
+    pub removed: Vec<Staged>,
+}
+
+/// Streaming stage removing some records of an output.
+///
+/// Records are pushed in coordinate order and emitted in the same order once
+/// their fate is final.
+pub trait RecordStage {
+    /// Push a record and move the records whose decision is final to
+    /// `emitted`.
+    fn push(&mut self, staged: Staged, emitted: &mut Emitted);
+
+    /// Decide all buffered records and move them to `emitted`.
+    fn flush(&mut self, emitted: &mut Emitted);
+
+    /// Describe the records removed by the stage so far.
+    fn summary(&self) -> String;
+}
+
+pub trait OutputHeader {
+    fn output_header(&self, header: &VCFHeader) -> VCFHeader;
+}
+
+/// Make an INFO header line.
+pub fn info_header_line(
+    id: &str,
+    number: &str,
+    value_type: &str,
+    description: &str,
+) -> VCFHeaderLine {
+    let line = format!(
+        "##INFO=<ID={},Number={},Type={},Description=\"{}\">\n",
+        id, number, value_type, description
+    );
+    VCFHeaderLine::from_bytes(line.as_bytes(), 0).expect("Invalid INFO header line")
+}
+
+/// Make a FILTER header line.
+pub fn filter_header_line(id: &str, description: &str) -> VCFHeaderLine {
+    let line = format!("##FILTER=<ID={},Description=\"{}\">\n", id, description);
+    VCFHeaderLine::from_bytes(line.as_bytes(), 0).expect("Invalid FILTER header line")
+}
+
+/// Append header lines to a VCF header.
+pub fn extend_header(header: &VCFHeader, lines: Vec<VCFHeaderLine>) -> VCFHeader {
+    let mut items = header.items().to_vec();
+    items.extend(lines);
+    VCFHeader::new(items, header.samples().to_vec())
+}
+
 pub fn launch_iostream(opt: Opt) {
     let ipath = opt.input.clone();
     let opath = opt.output.clone();
@@ -144,10 +260,24 @@ where
     T: AsRef<Path>,
     R: Read,
 {
+    let header = opt.output_header(vcf_reader.header());
+    if is_template(path) {
+        // the subcommand writes into the expanded paths itself
+        match VCFWriter::new(BufWriter::new(sink()), &header) {
+            Ok(vcf_writer) => {
+                opt.process(vcf_writer, vcf_reader);
+            }
+            Err(e) => {
+                error!("{}: '{}'", e, &path.as_ref().display());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     match stream_type(&path) {
         StreamType::Stdio => {
             if opt.gzip {
-                match writer_stdio_gz(&vcf_reader.header()) {
+                match writer_stdio_gz(&header) {
                     Ok(vcf_writer) => {
                         opt.process(vcf_writer, vcf_reader);
                     }
@@ -157,7 +287,7 @@ where
                     }
                 }
             } else {
-                match writer_stdio(&vcf_reader.header()) {
+                match writer_stdio(&header) {
                     Ok(vcf_writer) => {
                         opt.process(vcf_writer, vcf_reader);
                     }
@@ -170,7 +300,7 @@ where
         }
         StreamType::File => match compress_type(&path, opt.gzip) {
             CompressionType::Gzip | CompressionType::Bgzip => {
-                match writer_file_gz(&path, &vcf_reader.header()) {
+                match writer_file_gz(&path, &header) {
                     Ok(vcf_writer) => {
                         opt.process(vcf_writer, vcf_reader);
                     }
@@ -180,7 +310,7 @@ where
                     }
                 }
             }
-            CompressionType::None => match writer_file(&path, &vcf_reader.header()) {
+            CompressionType::None => match writer_file(&path, &header) {
                 Ok(vcf_writer) => {
                     opt.process(vcf_writer, vcf_reader);
                 }
@@ -193,6 +323,33 @@ where
     }
 }
 
+/// VCF writer over an output stream determined at runtime.
+pub type DynWriter = VCFWriter<BufWriter<Box<dyn Write>>>;
+
+/// Open a VCF writer by path.
+///
+/// The output is written to stdout if the path is `-`. Otherwise, the
+/// compression type is detected by [`compress_type`] unless it is forced.
+pub fn writer_path<T>(path: &T, header: &VCFHeader, force: bool) -> Result<DynWriter, VCFError>
+where
+    T: AsRef<Path>,
+{
+    let stream: Box<dyn Write> = match stream_type(path) {
+        StreamType::Stdio if force => Box::new(GzEncoder::new(stdout(), Compression::default())),
+        StreamType::Stdio => Box::new(stdout()),
+        StreamType::File => {
+            let file = File::create(path)?;
+            match compress_type(path, force) {
+                CompressionType::Gzip | CompressionType::Bgzip => {
+                    Box::new(GzEncoder::new(file, Compression::default()))
+                }
+                CompressionType::None => Box::new(file),
+            }
+        }
+    };
+    VCFWriter::new(BufWriter::new(stream), header)
+}
+
 pub fn writer_file<T>(path: &T, header: &VCFHeader) -> Result<VCFWriter<BufWriter<File>>, VCFError>
 where
     T: AsRef<Path>,
@@ -274,16 +431,7 @@ where
 pub fn is_gzipped_stdin(lstdin: &mut Stdin) -> bool {
     let mut lock = lstdin.lock();
     let buf = lock.fill_buf().unwrap();
-    match buf[0] {
-        0x1f => match buf[1] {
-            0x8b => {
-                return true;
-            }
-            _ => {}
-        },
-        _ => {}
-    }
-    false
+    is_gzip_magic(buf)
 }
 
 pub fn is_gzipped_file<T>(path: &T) -> Result<bool, std::io::Error>
@@ -291,21 +439,33 @@ where
     T: AsRef<Path>,
 {
     let mut reader = BufReader::new(File::open(path)?);
-    let mut itr = reader.fill_buf().into_iter().peekable();
-    let values = itr.peek().unwrap();
-    if values[0] == 0x1f && values[1] == 0x8b {
-        Ok(true)
-    } else {
-        Ok(false)
-    }
+    Ok(is_gzip_magic(reader.fill_buf()?))
+}
+
+/// Check whether a buffer starts with the gzip magic bytes; shorter buffers,
+/// e.g. of empty files, are taken as plain text.
+fn is_gzip_magic(values: &[u8]) -> bool {
+    values.len() >= 2 && values[0] == 0x1f && values[1] == 0x8b
 }
 
+/// Genotype of a sample as allele indices; i.e. 0 for REF and `i` for the
+/// `i`-th ALT allele.
 pub enum Genotype {
-    Phased(Vec<bool>),
-    Unphased(Vec<bool>),
+    Phased(Vec<usize>),
+    Unphased(Vec<usize>),
     Missing,
 }
 
+impl Genotype {
+    /// Check whether the genotype carries an allele; `None` if missing.
+    pub fn carries(&self, allele: usize) -> Option<bool> {
+        match self {
+            Genotype::Phased(v) | Genotype::Unphased(v) => Some(v.contains(&allele)),
+            Genotype::Missing => None,
+        }
+    }
+}
+
 pub enum VCFUtilError {
     ParseError(String),
 }
@@ -319,50 +479,53 @@ impl std::fmt::Display for VCFUtilError {
 }
 
 /// Parse VCF string value to Genotype
+///
+/// A genotype with any missing allele, e.g. `./1`, is considered missing.
+/// Genotypes of any ploidy are accepted; haploid ones are phased.
 pub fn parse_genotype(field: Option<&Vec<Vec<u8>>>) -> Result<Genotype, VCFUtilError> {
+    let value = match field.and_then(|v| v.first()) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Ok(Genotype::Missing),
+    };
+    let phased = !value.contains(&b'/');
     let mut genotype = Vec::new();
-    let mut phased = true;
-    match field {
-        Some(v) => {
-            let value = &v[0];
-            if value[0] == b'.' {
-                return Ok(Genotype::Missing);
-            }
-
-            for g in value {
-                match g {
-                    b'0' => {
-                        genotype.push(false);
-                    }
-                    b'1' => {
-                        genotype.push(true);
-                    }
-                    b'/' => {
-                        phased = false;
-                    }
-                    b'|' => {
-                        continue;
-                    }
-                    _ => {
-                        let msg = format!(
-                            "Cannot parse genotype value: '{}'",
-                            std::str::from_utf8(value).unwrap()
-                        );
-                        return Err(VCFUtilError::ParseError(msg));
-                    }
-                }
-            }
-
-            match phased {
-                true => Ok(Genotype::Phased(genotype)),
-                false => Ok(Genotype::Unphased(genotype)),
+    for allele in value.split(|c| *c == b'/' || *c == b'|') {
+        if allele == b"." {
+            return Ok(Genotype::Missing);
+        }
+        match std::str::from_utf8(allele)
+            .ok()
+            .and_then(|a| a.parse::<usize>().ok())
+        {
+            Some(idx) => genotype.push(idx),
+            None => {
+                let msg = format!(
+                    "Cannot parse genotype value: '{}'",
+                    String::from_utf8_lossy(value)
+                );
+                return Err(VCFUtilError::ParseError(msg));
             }
         }
-        None => Ok(Genotype::Missing),
+    }
+    match phased {
+        true => Ok(Genotype::Phased(genotype)),
+        false => Ok(Genotype::Unphased(genotype)),
+    }
+}
+
+/// Get the phase set (FORMAT/PS) of a sample in a record.
+///
+/// It returns an empty phase set for records without FORMAT/PS or with a
+/// missing value; as the VCF specification puts all phased genotypes without
+/// a phase set in the same one.
+pub fn phase_set<'a>(record: &'a VCFRecord, sample: &[u8]) -> &'a [u8] {
+    match record.genotype(sample, b"PS").and_then(|v| v.first()) {
+        Some(ps) if ps != b"." => ps,
+        _ => &[],
     }
 }
 
-pub fn unwrap_genotype(result: Result<Genotype, VCFUtilError>, sample: &Vec<u8>) -> Genotype {
+pub fn unwrap_genotype(result: Result<Genotype, VCFUtilError>, sample: &[u8]) -> Genotype {
     match result {
         Ok(v) => v,
         Err(e) => {
@@ -375,3 +538,307 @@ pub fn unwrap_genotype(result: Result<Genotype, VCFUtilError>, sample: &Vec<u8>)
         }
     }
 }
+
+/// Variant class of a record determined from its REF and ALT alleles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VariantClass {
+    /// Single or multiple nucleotide substitutions
+    Snv,
+    /// Insertions and deletions shorter than the SV length threshold
+    Indel,
+    /// Structural variants; i.e. symbolic alleles, breakends, or long indels
+    Sv,
+}
+
+impl VariantClass {
+    pub const ALL: [VariantClass; 3] = [VariantClass::Snv, VariantClass::Indel, VariantClass::Sv];
+
+    /// Index of the class in [`VariantClass::ALL`].
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VariantClass::Snv => "snv",
+            VariantClass::Indel => "indel",
+            VariantClass::Sv => "sv",
+        }
+    }
+}
+
+/// Check whether an ALT allele is symbolic (e.g. `<DEL>`) or a breakend.
+pub fn is_symbolic(allele: &[u8]) -> bool {
+    allele.starts_with(b"<") || allele.contains(&b'[') || allele.contains(&b']')
+}
+
+/// Classify a record by its REF and ALT alleles.
+///
+/// # Arguments
+///
+/// `record` - VCF record
+/// `sv_min_len` - minimum length difference between REF and an ALT allele for
+///   a non-symbolic allele to be considered as structural variant
+pub fn variant_class(record: &VCFRecord, sv_min_len: usize) -> VariantClass {
+    let mut class = VariantClass::Snv;
+    for allele in &record.alternative {
+        if is_symbolic(allele) {
+            return VariantClass::Sv;
+        }
+        if allele == b"*" || allele == b"." {
+            continue;
+        }
+        let diff = allele.len().abs_diff(record.reference.len());
+        if diff >= sv_min_len {
+            return VariantClass::Sv;
+        } else if diff > 0 {
+            class = VariantClass::Indel;
+        }
+    }
+    class
+}
+
+/// Get the genotypes of a given ploidy over a number of alleles in the order
+/// of FORMAT fields with one value per genotype (Number=G).
+fn genotype_order(ploidy: usize, nof_alleles: usize) -> Vec<Vec<usize>> {
+    if ploidy == 0 {
+        return vec![Vec::new()];
+    }
+    let mut genotypes = Vec::new();
+    for last in 0..nof_alleles {
+        for mut genotype in genotype_order(ploidy - 1, last + 1) {
+            genotype.push(last);
+            genotypes.push(genotype);
+        }
+    }
+    genotypes
+}
+
+/// Keep only the given ALT alleles of a record.
+///
+/// The removed alleles in genotypes are replaced by the reference allele and
+/// the kept ones are renumbered. INFO and FORMAT fields with one value per ALT
+/// allele (Number=A), per allele (Number=R), or per genotype (Number=G) are
+/// trimmed accordingly.
+///
+/// # Arguments
+///
+/// * `record` - VCF record to be trimmed
+/// * `kept` - Whether to keep each ALT allele of the record
+pub fn trim_alt_alleles(record: &VCFRecord, kept: &[bool]) -> VCFRecord {
+    let header = record.header();
+    let nof_alleles = record.alternative.len() + 1;
+    // kept alleles including REF and their new indices
+    let alleles: Vec<usize> = (0..nof_alleles)
+        .filter(|a| *a == 0 || kept[a - 1])
+        .collect();
+    let mut renumber = vec![0; nof_alleles];
+    for (new, old) in alleles.iter().enumerate() {
+        renumber[*old] = new;
+    }
+    let select = |values: &Vec<Vec<u8>>, indices: &[usize]| -> Vec<Vec<u8>> {
+        indices.iter().map(|i| values[*i].clone()).collect()
+    };
+    let alt_indices: Vec<usize> = alleles[1..].iter().map(|a| a - 1).collect();
+    let resize = |number: &Number, values: &Vec<Vec<u8>>, ploidy: Option<usize>| match number {
+        Number::Allele if values.len() == nof_alleles - 1 => Some(select(values, &alt_indices)),
+        Number::Reference if values.len() == nof_alleles => Some(select(values, &alleles)),
+        Number::Genotype => {
+            let order = genotype_order(ploidy?, nof_alleles);
+            if values.len() != order.len() {
+                return None;
+            }
+            let indices: Vec<usize> = (0..order.len())
+                .filter(|i| order[*i].iter().all(|a| *a == 0 || kept[a - 1]))
+                .collect();
+            Some(select(values, &indices))
+        }
+        _ => None,
+    };
+
+    let mut trimmed = record.clone();
+    trimmed.alternative = select(&record.alternative, &alt_indices);
+    for (key, values) in trimmed.info.iter_mut() {
+        if let Some(info) = header.info(key) {
+            if let Some(resized) = resize(info.number, values, None) {
+                *values = resized;
+            }
+        }
+    }
+    for sample in trimmed.genotype.iter_mut() {
+        let gt_idx = record.format.iter().position(|k| k == b"GT");
+        let ploidy = gt_idx
+            .and_then(|i| sample.get(i))
+            .and_then(|v| v.first())
+            .map(|gt| gt.split(|c| *c == b'/' || *c == b'|').count());
+        for (idx, values) in sample.iter_mut().enumerate() {
+            let key = &record.format[idx];
+            if Some(idx) == gt_idx {
+                for gt in values.iter_mut() {
+                    *gt = renumber_genotype(gt, &renumber);
+                }
+            } else if let Some(format) = header.format(key) {
+                if let Some(resized) = resize(format.number, values, ploidy) {
+                    *values = resized;
+                }
+            }
+        }
+    }
+    trimmed
+}
+
+/// Replace the alleles of the given haplotypes in a GT value.
+///
+/// # Arguments
+///
+/// * `value` - GT value
+/// * `replaced` - Whether to replace the allele of each haplotype
+/// * `allele` - Allele to put in place of the replaced ones; e.g. `0` or `.`
+pub fn replace_alleles(value: &[u8], replaced: &[bool], allele: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut offset = 0;
+    for (hap, old) in value.split(|c| *c == b'/' || *c == b'|').enumerate() {
+        if offset > 0 {
+            result.push(value[offset - 1]);
+        }
+        offset += old.len() + 1;
+        match replaced.get(hap) {
+            Some(true) => result.extend(allele),
+            _ => result.extend(old),
+        }
+    }
+    result
+}
+
+/// Renumber the alleles of a GT value; missing and invalid alleles are kept.
+fn renumber_genotype(value: &[u8], renumber: &[usize]) -> Vec<u8> {
+    let mut renumbered = Vec::with_capacity(value.len());
+    let mut offset = 0;
+    for allele in value.split(|c| *c == b'/' || *c == b'|') {
+        if offset > 0 {
+            // separator preceding this allele
+            renumbered.push(value[offset - 1]);
+        }
+        offset += allele.len() + 1;
+        match std::str::from_utf8(allele)
+            .ok()
+            .and_then(|a| a.parse::<usize>().ok())
+            .and_then(|a| renumber.get(a))
+        {
+            Some(new) => renumbered.extend(format!("{}", new).as_bytes()),
+            None => renumbered.extend(allele),
+        }
+    }
+    renumbered
+}
+
+/// Get the FILTER values of a record.
+///
+/// The VCF parser splits FILTER only at ',' while the values are separated by
+/// ';' in the specification; so each parsed entry is split again.
+pub fn filter_values(record: &VCFRecord) -> impl Iterator<Item = &[u8]> {
+    record
+        .filter
+        .iter()
+        .flat_map(|f| f.split(|c| *c == b';'))
+        .filter(|f| !f.is_empty())
+}
+
+/// Parse the header and records of a VCF text; for tests.
+#[cfg(test)]
+pub fn parse_vcf(text: &str) -> (VCFHeader, Vec<VCFRecord>) {
+    let mut reader = VCFReader::new(BufReader::new(text.as_bytes())).unwrap();
+    let header = reader.header().clone();
+    let mut records = Vec::new();
+    let mut record = VCFRecord::new(header.clone());
+    while reader.next_record(&mut record).unwrap() {
+        records.push(record.clone());
+    }
+    (header, records)
+}
+
+/// Make a deterministic pseudo-random number generator (xorshift64) from a
+/// nonzero seed; for tests.
+#[cfg(test)]
+pub fn xorshift(seed: u64) -> impl FnMut() -> u64 {
+    let mut state = seed;
+    move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genotype_order() {
+        assert_eq!(genotype_order(1, 3), vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(
+            genotype_order(2, 3),
+            vec![
+                vec![0, 0],
+                vec![0, 1],
+                vec![1, 1],
+                vec![0, 2],
+                vec![1, 2],
+                vec![2, 2]
+            ]
+        );
+        assert_eq!(
+            genotype_order(3, 2),
+            vec![vec![0, 0, 0], vec![0, 0, 1], vec![0, 1, 1], vec![1, 1, 1]]
+        );
+    }
+
+    #[test]
+    fn test_renumber_genotype() {
+        let renumber = |value: &str| {
+            String::from_utf8(renumber_genotype(value.as_bytes(), &[0, 1, 0, 2])).unwrap()
+        };
+        assert_eq!(renumber("0|2"), "0|0");
+        assert_eq!(renumber("1/3"), "1/2");
+        assert_eq!(renumber("3|1"), "2|1");
+        assert_eq!(renumber("./3"), "./2");
+        assert_eq!(renumber("."), ".");
+        assert_eq!(renumber("3"), "2");
+        // invalid alleles are kept
+        assert_eq!(renumber("x/4"), "x/4");
+        let renumbered = renumber_genotype(b"10/1", &(0..11).rev().collect::<Vec<_>>());
+        assert_eq!(renumbered, b"0/9");
+    }
+
+    #[test]
+    fn test_trim_alt_alleles() {
+        let (_, records) = parse_vcf(
+            "##fileformat=VCFv4.2
+##INFO=<ID=AC,Number=A,Type=Integer,Description=\"AC\">
+##INFO=<ID=AF,Number=R,Type=Float,Description=\"AF\">
+##INFO=<ID=NS,Number=1,Type=Integer,Description=\"NS\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"GT\">
+##FORMAT=<ID=AD,Number=R,Type=Integer,Description=\"AD\">
+##FORMAT=<ID=PL,Number=G,Type=Integer,Description=\"PL\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ta\tb\tc\td
+x\t1\t.\tA\tC,G,T\t.\t.\tAC=1,2,3;AF=0.4,0.1,0.2,0.3;NS=4\tGT:AD:PL\t0|2:10,1,2,3:0,1,2,3,4,5,6,7,8,9\t1/3:10,1,2,3:0,1,2,3,4,5,6,7,8,9\t3:10,1,2,3:0,1,2,3\t./.:.:.
+",
+        );
+        // drop the middle ALT allele
+        let trimmed = trim_alt_alleles(&records[0], &[true, false, true]);
+        let join = |values: &Vec<Vec<u8>>| String::from_utf8(values.join(&b","[..])).unwrap();
+        assert_eq!(trimmed.alternative, vec![b"C".to_vec(), b"T".to_vec()]);
+        assert_eq!(join(trimmed.info(b"AC").unwrap()), "1,3");
+        assert_eq!(join(trimmed.info(b"AF").unwrap()), "0.4,0.1,0.3");
+        assert_eq!(join(trimmed.info(b"NS").unwrap()), "4");
+        let format =
+            |sample: usize| -> Vec<String> { trimmed.genotype[sample].iter().map(join).collect() };
+        // Number=G of ploidy 2 keeps 0/0, 0/1, 1/1, 0/3, 1/3, 3/3
+        assert_eq!(format(0), vec!["0|0", "10,1,3", "0,1,2,6,7,9"]);
+        assert_eq!(format(1), vec!["1/2", "10,1,3", "0,1,2,6,7,9"]);
+        // Number=G of ploidy 1 keeps 0, 1, 3
+        assert_eq!(format(2), vec!["2", "10,1,3", "0,1,3"]);
+        assert_eq!(format(3), vec!["./.", ".", "."]);
+    }
+}